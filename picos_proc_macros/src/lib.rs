@@ -13,11 +13,43 @@ use syn::{
 use proc_macro2::{Punct, Spacing, Span, TokenStream, TokenTree};
 
 #[proc_macro_attribute]
-pub fn task(_: proc_macro::TokenStream, input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+pub fn task(
+    attr: proc_macro::TokenStream,
+    input: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
     let mut output = TokenStream::new();
 
     let input_function: ItemFn = syn::parse(input).unwrap();
 
+    // Task options such as `#[task(stack_size = 512)]` override the defaults of the TaskArgument trait
+    let mut periodic = false;
+    let task_arg_items = parse_options(&mut attr.into_iter())
+        .into_iter()
+        .map(|(key, value)| {
+            let (const_name, const_type, value) = match key.to_string().as_str() {
+                "stack_size" => ("STACK_SIZE", "usize", value),
+                "period_us" => {
+                    periodic = true;
+                    ("PERIOD_US", "Option<u32>", wrap_in_some(value))
+                }
+                _ => panic!("Unknown task option `{}`", key),
+            };
+
+            syn::ImplItem::Const(syn::ImplItemConst {
+                attrs: vec![],
+                vis: syn::Visibility::Inherited,
+                defaultness: None,
+                const_token: syn::token::Const::default(),
+                ident: Ident::new(const_name, key.span()),
+                colon_token: Colon::default(),
+                ty: syn::Type::Verbatim(TokenStream::from_str(const_type).unwrap()),
+                eq_token: syn::token::Eq::default(),
+                expr: value,
+                semi_token: syn::token::Semi::default(),
+            })
+        })
+        .collect();
+
     // We are extracting our fields from the function input here so we can convert them to Fields of a struct
     let mut named_fields = Punctuated::new();
     input_function
@@ -166,7 +198,7 @@ pub fn task(_: proc_macro::TokenStream, input: proc_macro::TokenStream) -> proc_
     // Put our impl block in our token stream
     new_impl.to_tokens(&mut output);
 
-    // Build our empty Impl of TaskArgument for each created structure
+    // Build our Impl of TaskArgument for each created structure, it is empty unless task options were given
     let task_arg_impl = ItemImpl {
         attrs: input_function.attrs.clone(),
         defaultness: None,
@@ -189,7 +221,7 @@ pub fn task(_: proc_macro::TokenStream, input: proc_macro::TokenStream) -> proc_
             }),
         })),
         brace_token: Brace::default(),
-        items: vec![],
+        items: task_arg_items,
     };
 
     // Put our impl block in our token stream
@@ -285,6 +317,37 @@ pub fn task(_: proc_macro::TokenStream, input: proc_macro::TokenStream) -> proc_
         }
         _ => panic!("Untyped function argument in input function is not acceptable"),
     });
+    // A periodic task's body is a single job, so it is run once per period forever after the arguments are bound
+    if periodic {
+        let mut job = core::mem::take(&mut modified_function.block.stmts);
+        // The body's tail expression is no longer the last statement once the wait is added after it
+        if let Some(syn::Stmt::Expr(tail)) = job.last().cloned() {
+            *job.last_mut().unwrap() = syn::Stmt::Semi(tail, syn::token::Semi::default());
+        }
+        job.push(syn::Stmt::Semi(
+            syn::Expr::Verbatim(
+                TokenStream::from_str("crate::services::scheduler::wait_next_period()").unwrap(),
+            ),
+            syn::token::Semi::default(),
+        ));
+
+        modified_function.block.stmts = vec![syn::Stmt::Expr(syn::Expr::Loop(syn::ExprLoop {
+            attrs: vec![],
+            label: None,
+            loop_token: syn::token::Loop::default(),
+            body: syn::Block {
+                brace_token: Brace::default(),
+                stmts: job,
+            },
+        }))];
+        modified_function.sig.output = syn::ReturnType::Type(
+            syn::token::RArrow::default(),
+            Box::new(syn::Type::Never(syn::TypeNever {
+                bang_token: syn::token::Bang::default(),
+            })),
+        );
+    }
+
     rebind_statements.append(&mut modified_function.block.stmts);
     modified_function.block.stmts = rebind_statements;
 
@@ -313,8 +376,11 @@ pub fn add_task(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     ));
     input_iter.next().unwrap();
 
-    // Parse the function to get the function name
-    let func: syn::ExprCall = syn::parse(input_iter.collect()).unwrap();
+    // Parse the function to get the function name, anything after the next top level , is a task option
+    let func: syn::ExprCall = syn::parse(take_until_comma(&mut input_iter)).unwrap();
+
+    // Collect the optional `key = value` task options, such as `priority = 2`
+    let options = parse_options(&mut input_iter);
     let argument_structure = match *func.clone().func {
         syn::Expr::Path(path) => {
             format!("_{}Arguments", path.path.segments.last().unwrap().ident)
@@ -375,9 +441,7 @@ pub fn add_task(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
         arguments: syn::PathArguments::None,
     });
 
-    // Build the arguments to Task::new
-    let mut args: Punctuated<syn::Expr, Comma> = Punctuated::new();
-    args.push(syn::Expr::Call(syn::ExprCall {
+    let mut task_new = syn::Expr::Call(syn::ExprCall {
         attrs: vec![],
         func: Box::new(syn::Expr::Path(syn::ExprPath {
             attrs: vec![],
@@ -389,9 +453,28 @@ pub fn add_task(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
         })),
         paren_token: syn::token::Paren::default(),
         args: task_new_args,
-    }));
+    });
+
+    // Each task option becomes a builder call on the new task, so `priority = 2` emits `.with_priority(2)`
+    for (key, value) in options {
+        let mut option_args = Punctuated::new();
+        option_args.push(value);
+        task_new = syn::Expr::MethodCall(ExprMethodCall {
+            attrs: vec![],
+            receiver: Box::new(task_new),
+            dot_token: syn::token::Dot::default(),
+            method: Ident::new(&format!("with_{}", key), key.span()),
+            turbofish: None,
+            paren_token: syn::token::Paren::default(),
+            args: option_args,
+        });
+    }
 
-    // This is the single method call we actually need to emit in the form rougly of scheduler.add_task(Task::new(TASK_NAME, TASK_FUCTION_PTR, TASK_ARG_STRUCT))
+    // Build the arguments to Task::new
+    let mut args: Punctuated<syn::Expr, Comma> = Punctuated::new();
+    args.push(task_new);
+
+    // This is the single method call we actually need to emit in the form rougly of scheduler.add_task(Task::new(TASK_NAME, TASK_FUCTION_PTR, TASK_ARG_STRUCT).with_OPTION(VALUE))
     let method_call = ExprMethodCall {
         attrs: vec![],
         receiver: Box::new(syn::Expr::Path(syn::ExprPath {
@@ -415,3 +498,51 @@ pub fn add_task(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
 
     output.into()
 }
+
+// Parses a comma separated list of `key = value` task options until the tokens run out
+fn parse_options(
+    input_iter: &mut impl Iterator<Item = proc_macro::TokenTree>,
+) -> Vec<(Ident, syn::Expr)> {
+    let mut options = vec![];
+    while let Some(key) = input_iter.next() {
+        let key = Ident::new(&key.to_string(), Span::call_site());
+        match input_iter.next() {
+            Some(proc_macro::TokenTree::Punct(punct)) if punct.as_char() == '=' => (),
+            _ => panic!("Task options must be in the form `key = value`"),
+        }
+        let value: syn::Expr = syn::parse(take_until_comma(input_iter)).unwrap();
+        options.push((key, value));
+    }
+    options
+}
+
+// Collects tokens up to (and consuming) the next top level `,`, groups such as `(..)` are a single token so nested commas are kept
+fn take_until_comma(
+    input_iter: &mut impl Iterator<Item = proc_macro::TokenTree>,
+) -> proc_macro::TokenStream {
+    input_iter
+        .take_while(
+            |token| !matches!(token, proc_macro::TokenTree::Punct(punct) if punct.as_char() == ','),
+        )
+        .collect()
+}
+
+// Turns `value` into `Some(value)` for options stored as an `Option` in the TaskArgument trait
+fn wrap_in_some(value: syn::Expr) -> syn::Expr {
+    let mut args = Punctuated::new();
+    args.push(value);
+
+    syn::Expr::Call(syn::ExprCall {
+        attrs: vec![],
+        func: Box::new(syn::Expr::Path(syn::ExprPath {
+            attrs: vec![],
+            qself: None,
+            path: syn::Path::from(PathSegment {
+                ident: Ident::new("Some", Span::call_site()),
+                arguments: syn::PathArguments::None,
+            }),
+        })),
+        paren_token: syn::token::Paren::default(),
+        args,
+    })
+}
@@ -1,3 +1,18 @@
-pub const MAX_TASKS: usize = 4;
+// Upper bound on live tasks per core, the task table itself is heap allocated and grows as tasks are added
+pub const MAX_TASKS: usize = 8;
+// Default task stack size in words, tasks can override it with `#[task(stack_size = ...)]`
 pub const TASK_STACK_SIZE: usize = 256;
+// Pattern written to the bottom of every task stack and checked on each context switch
+pub const STACK_CANARY: u32 = 0xDEAD_BEEF;
+pub const STACK_CANARY_WORDS: usize = 4;
+// Pattern every task stack is painted with so the high-water mark can be found by looking for untouched words
+pub const STACK_PAINT: u32 = 0xA5A5_A5A5;
 pub const HEAP_SIZE: usize = 16 * 1024;
+// Messages a task's mailbox holds before sends apply its `SendPolicy`, tasks can override it with `with_mailbox_capacity`
+pub const MAILBOX_CAPACITY: usize = 8;
+// Slots in each core's queue of messages posted from interrupt handlers, and the most bytes one of them can carry
+pub const ISR_QUEUE_SIZE: usize = 16;
+pub const ISR_MESSAGE_SIZE: usize = 8;
+pub const NUM_CORES: usize = 2;
+// Stack in words core 1 boots on, it is only used until core 1 switches into its first task
+pub const CORE1_STACK_SIZE: usize = 512;
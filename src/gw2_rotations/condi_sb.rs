@@ -3,7 +3,7 @@ use crate::services::{
     task::TaskArgument,
 };
 
-use crate::bsp::hal::timer::Timer;
+use crate::services::scheduler::sleep_us;
 use crate::services::usb;
 
 use cortex_m::interrupt::{free, Mutex};
@@ -119,7 +119,7 @@ pub fn run_rotation() -> ! {
     let mut twice_as_vicious = TalentTimer::new(10_000_000);
 
     // delay.delay_ms(3000);
-    timer_delay_ms(&timer, 3000);
+    sleep_us(3000 * 1000);
 
     loop {
         if one_wolf_pack.is_ready(&timer) {
@@ -137,9 +137,9 @@ pub fn run_rotation() -> ! {
             //     None,
             //     None,
             // ));
-            // timer_delay_us(&timer, KEY_PRESS_DELAY);
+            // sleep_us(KEY_PRESS_DELAY);
             // usb::send(&RELEASE_ALL);
-            // timer_delay_us(&timer, KEY_PRESS_DELAY >> 2);
+            // sleep_us(KEY_PRESS_DELAY >> 2);
             // vulture_stance.ready_at =
             //     (timer.get_counter_low() as i32).wrapping_add(vulture_stance.cooldown);
         } else if sharpening_stone.is_ready(&timer) {
@@ -160,25 +160,7 @@ pub fn run_rotation() -> ! {
             }
         } else {
             // crossfire.use_skill(&timer, &mut delay)
-            timer_delay_ms(&timer, 540);
-        }
-    }
-}
-
-fn timer_delay_ms(timer: &Timer, ms: u32) {
-    let end = timer.get_counter_low() + (ms * 1000);
-    loop {
-        if timer.get_counter_low() >= end {
-            break;
-        }
-    }
-}
-
-fn timer_delay_us(timer: &Timer, us: u32) {
-    let end = timer.get_counter_low() + us;
-    loop {
-        if timer.get_counter_low() >= end {
-            break;
+            sleep_us(540 * 1000);
         }
     }
 }
@@ -1,10 +1,8 @@
-use super::{
-    timer_delay_ms, timer_delay_us, KeyboardReport, Skill, TalentTimer, TaskArgument, WeaponSwap,
-    MOD_KEY, TIMER,
-};
+use super::{KeyboardReport, Skill, TalentTimer, TaskArgument, WeaponSwap, MOD_KEY, TIMER};
 use crate::Box;
 
 use crate::bsp::hal::timer::Timer;
+use crate::services::scheduler::sleep_us;
 
 pub struct RunRotationArguments<'a> {
     timer: &'a Timer,
@@ -200,7 +198,7 @@ pub fn run_rotation(args: Box<RunRotationArguments>) -> ! {
         1_000_000,
     );
 
-    timer_delay_ms(&timer, 3000);
+    sleep_us(3000 * 1000);
 
     loop {
         splitblade.use_skill(&timer);
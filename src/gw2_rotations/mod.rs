@@ -5,6 +5,7 @@ use crate::services::{
 
 use crate::bsp::hal::timer::Timer;
 use crate::debug;
+use crate::services::scheduler::{sleep_until, sleep_us};
 use crate::services::usb;
 
 use crate::TIMER;
@@ -19,24 +20,6 @@ const COOLDOWN_FUDGE_FACTOR: u32 = 120_000;
 const HAS_ALACRITY: bool = true;
 const HAS_QUICKNESS: bool = true;
 
-fn timer_delay_ms(timer: &Timer, ms: u32) {
-    let end = timer.get_counter_low() + (ms * 1000);
-    loop {
-        if timer.get_counter_low() >= end {
-            break;
-        }
-    }
-}
-
-fn timer_delay_us(timer: &Timer, us: u32) {
-    let end = timer.get_counter_low() + us;
-    loop {
-        if timer.get_counter_low() >= end {
-            break;
-        }
-    }
-}
-
 struct Skill<'a> {
     name: &'a str,
     activation_keys: KeyboardReport,
@@ -66,12 +49,12 @@ impl<'a> Skill<'a> {
 
     fn use_skill(&mut self, timer: &Timer) {
         usb::send(&self.activation_keys);
-        timer_delay_us(&timer, KEY_PRESS_DELAY);
+        sleep_us(KEY_PRESS_DELAY);
         usb::send(&RELEASE_ALL);
         if self.activation != 0 {
-            timer_delay_us(&timer, self.activation);
+            sleep_us(self.activation);
         } else {
-            timer_delay_us(&timer, KEY_PRESS_DELAY >> 4);
+            sleep_us(KEY_PRESS_DELAY >> 4);
         }
         self.ready_at = (timer.get_counter_low() as i32)
             .wrapping_add(self.cooldown)
@@ -88,8 +71,10 @@ impl<'a> Skill<'a> {
     }
 
     fn blocking_use_skill(&mut self, timer: &Timer) {
-        // Block until ready
-        while !self.is_ready(&timer) {}
+        // Sleep until ready
+        while !self.is_ready(&timer) {
+            sleep_until(self.ready_at as u32);
+        }
 
         self.use_skill(&timer);
     }
@@ -131,18 +116,20 @@ impl WeaponSwap {
 
     fn use_skill(&mut self, timer: &Timer) {
         usb::send(&self.activation_keys);
-        timer_delay_us(&timer, KEY_PRESS_DELAY);
+        sleep_us(KEY_PRESS_DELAY);
         usb::send(&RELEASE_ALL);
         if self.activation != 0 {
-            timer_delay_us(&timer, self.activation);
+            sleep_us(self.activation);
         } else {
-            timer_delay_us(&timer, KEY_PRESS_DELAY >> 4);
+            sleep_us(KEY_PRESS_DELAY >> 4);
         }
         self.ready_at = (timer.get_counter_low() as i32).wrapping_add(self.cooldown);
     }
     fn blocking_use_skill(&mut self, timer: &Timer) {
-        // Block until ready
-        while !self.is_ready(&timer) {}
+        // Sleep until ready
+        while !self.is_ready(&timer) {
+            sleep_until(self.ready_at as u32);
+        }
 
         self.use_skill(&timer)
     }
@@ -1,7 +1,5 @@
-use super::{
-    timer_delay_ms, timer_delay_us, KeyboardReport, Skill, TalentTimer, TaskArgument, WeaponSwap,
-    MOD_KEY, TIMER,
-};
+use super::{KeyboardReport, Skill, TalentTimer, TaskArgument, WeaponSwap, MOD_KEY, TIMER};
+use crate::services::scheduler::sleep_us;
 
 pub struct RunRotationArguments {}
 unsafe impl Send for RunRotationArguments {}
@@ -146,7 +144,7 @@ pub fn run_rotation(args: &RunRotationArguments) -> ! {
         None,
     ));
 
-    timer_delay_ms(&timer, 3000);
+    sleep_us(3000 * 1000);
 
     frost_trap.blocking_use_skill(&timer);
     one_wolf_pack.blocking_use_skill(&timer);
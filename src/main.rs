@@ -30,7 +30,7 @@ use bsp::hal::{
     Clock,
 };
 
-use alloc_cortex_m::CortexMHeap;
+use embedded_time::duration::Microseconds;
 
 use services::{
     post_office::PostOffice,
@@ -50,18 +50,19 @@ mod sync;
 use alloc::boxed::Box;
 use constants::HEAP_SIZE;
 use gw2_rotations::condi_sb::{_run_rotationArguments, run_rotation};
-use sync::Spinlock;
+use sync::{Mutex, SharedHeap};
 
 #[global_allocator]
-static ALLOCATOR: CortexMHeap = CortexMHeap::empty();
+static ALLOCATOR: SharedHeap = SharedHeap::empty();
 
 #[entry]
 fn main() -> ! {
     // info!("Program start");
     let mut pac = pac::Peripherals::take().unwrap();
-    let core = pac::CorePeripherals::take().unwrap();
+    let mut core = pac::CorePeripherals::take().unwrap();
     let mut watchdog = Watchdog::new(pac.WATCHDOG);
-    let sio = Sio::new(pac.SIO);
+    let mut sio = Sio::new(pac.SIO);
+    unsafe { sync::release_kernel_spinlocks() };
 
     // Setup the heap
     {
@@ -70,8 +71,10 @@ fn main() -> ! {
         unsafe { ALLOCATOR.init(HEAP.as_ptr() as usize, HEAP_SIZE) }
     }
     services::post_office::PostOffice::init().unwrap();
-    let mut scheduler =
-        services::scheduler::Scheduler::new(services::scheduler::ScheduleType::RoundRobin(0x1000));
+    let mut scheduler = services::scheduler::Scheduler::new(
+        services::scheduler::ScheduleType::RateMonotonic(Microseconds(1_000)),
+    )
+    .with_tickless_idle();
 
     // Used for tracking the SysTick
     let pins = bsp::Pins::new(
@@ -115,7 +118,9 @@ fn main() -> ! {
         )
         .unwrap();
 
-    scheduler.add_task(services::uart::uart_task(uart)).unwrap();
+    scheduler
+        .add_task(services::uart::uart_task(uart).with_priority(1))
+        .unwrap();
 
     // Initialize USB
     let usb_bus = UsbBusAllocator::new(UsbBus::new(
@@ -144,7 +149,20 @@ fn main() -> ! {
     //     ))
     //     .unwrap();
 
-    add_task!(scheduler, "Enable LED", enable_led(true, 60_000_000)).unwrap();
+    // Core 1 gets the LED to itself while USB and UART stay on core 0
+    let mut core1_scheduler = services::scheduler::Scheduler::new(
+        services::scheduler::ScheduleType::RateMonotonic(Microseconds(1_000)),
+    )
+    .with_tickless_idle();
+
+    add_task!(
+        core1_scheduler,
+        "Enable LED",
+        enable_led(true, 500_000),
+        priority = 1,
+        core = 1
+    )
+    .unwrap();
 
     // scheduler
     //     .add_task(Task::new(
@@ -158,38 +176,34 @@ fn main() -> ! {
     //     .add_task(Task::new("Idle".into(), idle, _idleArguments {}))
     //     .unwrap();
 
-    add_task!(scheduler, "Idle", idle()).unwrap();
+    core1_scheduler
+        .launch_core1(&mut pac.PSM, &mut sio.fifo, clocks.system_clock.freq())
+        .unwrap();
 
-    scheduler.start(core.SYST).unwrap();
+    scheduler
+        .start(core.SYST, &mut core.SCB, clocks.system_clock.freq())
+        .unwrap();
 
     loop {}
 }
 
 use core::cell::RefCell;
-use cortex_m::interrupt::Mutex;
 
-static LED: Spinlock<RefCell<Option<Pin<Gpio25, Output<PushPull>>>>> =
-    Spinlock::new(RefCell::new(None));
-static TIMER: Spinlock<RefCell<Option<Timer>>> = Spinlock::new(RefCell::new(None));
+// The rotations hold the timer for as long as they run, so tasks waiting on these sleep in the scheduler instead
+// of spinning against a holder that may never get the CPU back
+static LED: Mutex<RefCell<Option<Pin<Gpio25, Output<PushPull>>>>> = Mutex::new(RefCell::new(None));
+static TIMER: Mutex<RefCell<Option<Timer>>> = Mutex::new(RefCell::new(None));
 
-#[task]
-pub fn enable_led(enable: bool, delay: u32) -> ! {
+// Blinks once a second, the LED stays lit for `on_us` of each period
+#[task(period_us = 1_000_000)]
+pub fn enable_led(enable: bool, on_us: u32) {
     if enable {
-        loop {
-            LED.lock().get_mut().as_mut().unwrap().set_high().unwrap();
-            debug!("LED On.");
-            cortex_m::asm::delay(delay);
-            LED.lock().get_mut().as_mut().unwrap().set_low().unwrap();
-            debug!("LED Off.");
-            cortex_m::asm::delay(delay);
-        }
+        LED.lock().get_mut().as_mut().unwrap().set_high().unwrap();
+        debug!("LED On.");
+        services::scheduler::sleep_us(on_us);
+        LED.lock().get_mut().as_mut().unwrap().set_low().unwrap();
+        debug!("LED Off.");
     }
-    loop {}
-}
-
-#[task]
-pub fn idle() -> ! {
-    loop {}
 }
 
 #[alloc_error_handler]
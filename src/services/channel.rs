@@ -0,0 +1,123 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use core::cell::RefCell;
+
+use crate::services::scheduler;
+use crate::sync::{deadline_after, NakedMutex, WaitList};
+
+struct ChannelState<T> {
+    queue: VecDeque<T>,
+    capacity: usize,
+    // Tasks waiting for a message, and for room to send one
+    receivers: WaitList,
+    senders: WaitList,
+}
+
+type Channel<T> = NakedMutex<RefCell<ChannelState<T>>>;
+
+/// Creates a typed channel holding up to `capacity` messages. Channels are meant to be made while setting up tasks
+/// and handed to them as arguments, so the shared state is allocated once and never freed.
+pub fn channel<T: Send + 'static>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "A channel has to hold at least one message");
+
+    let channel: &'static Channel<T> =
+        Box::leak(Box::new(NakedMutex::new(RefCell::new(ChannelState {
+            queue: VecDeque::with_capacity(capacity),
+            capacity,
+            receivers: WaitList::new(),
+            senders: WaitList::new(),
+        }))));
+
+    (Sender { channel }, Receiver { channel })
+}
+
+/// Sending half of a `channel`, it can be cloned to give several tasks a way to send
+pub struct Sender<T: 'static> {
+    channel: &'static Channel<T>,
+}
+
+impl<T: Send> Sender<T> {
+    /// Queues `value`, blocking while the channel is full
+    pub fn send(&self, value: T) {
+        // Without a deadline this can't time out, so the value is never handed back
+        self.send_timeout(value, None).ok();
+    }
+
+    /// Queues `value`, giving up after `timeout_us` microseconds (None waits forever). The value is handed back if
+    /// it timed out.
+    pub fn send_timeout(&self, value: T, timeout_us: Option<u32>) -> Result<(), T> {
+        let mut value = Some(value);
+
+        let sent = scheduler::wait_for(
+            self.channel,
+            deadline_after(timeout_us),
+            |state| &mut state.senders,
+            |state, _| {
+                if state.queue.len() < state.capacity {
+                    state.queue.push_back(value.take().unwrap());
+                    Some(())
+                } else {
+                    None
+                }
+            },
+        );
+
+        match sent {
+            Some(()) => {
+                // Waking only the first receiver would lose the message's wake if that one had been killed, so
+                // they all check and whoever loses the race blocks again
+                scheduler::notify(self.channel, |state| &mut state.receivers, true, |_| ());
+                Ok(())
+            }
+            None => Err(value.take().unwrap()),
+        }
+    }
+
+    /// Queues `value` if there is room without blocking, this is safe to call from interrupts since the queue never
+    /// grows past the capacity it was allocated with
+    pub fn try_send(&self, value: T) -> Result<(), T> {
+        self.send_timeout(value, Some(0))
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            channel: self.channel,
+        }
+    }
+}
+
+/// Receiving half of a `channel`
+pub struct Receiver<T: 'static> {
+    channel: &'static Channel<T>,
+}
+
+impl<T: Send> Receiver<T> {
+    /// Takes the oldest message, blocking until one is sent
+    pub fn recv(&self) -> T {
+        self.recv_timeout(None).unwrap()
+    }
+
+    /// Takes the oldest message, giving up after `timeout_us` microseconds (None waits forever). Returns None if it
+    /// timed out.
+    pub fn recv_timeout(&self, timeout_us: Option<u32>) -> Option<T> {
+        let value = scheduler::wait_for(
+            self.channel,
+            deadline_after(timeout_us),
+            |state| &mut state.receivers,
+            |state, _| state.queue.pop_front(),
+        );
+
+        if value.is_some() {
+            // Every blocked sender tries again, one that was woken and then killed can't strand the rest
+            scheduler::notify(self.channel, |state| &mut state.senders, true, |_| ());
+        }
+        value
+    }
+
+    /// Takes the oldest message if there is one without blocking, this is safe to call from interrupts
+    pub fn try_recv(&self) -> Option<T> {
+        self.recv_timeout(Some(0))
+    }
+}
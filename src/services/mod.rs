@@ -1,3 +1,4 @@
+pub mod channel;
 pub mod post_office;
 pub mod report;
 pub mod scheduler;
@@ -1,33 +1,75 @@
 use alloc;
 use core::{
+    any::Any,
     arch::asm,
-    cell::{Cell, RefCell},
+    cell::{Cell, RefCell, UnsafeCell},
+    sync::atomic::{AtomicUsize, Ordering},
 };
 use cortex_m::peripheral::{syst::SystClkSource, SYST};
 use cortex_m_rt::exception;
 
-use crate::constants::MAX_TASKS;
+use crate::constants::{ISR_MESSAGE_SIZE, ISR_QUEUE_SIZE, MAX_TASKS, NUM_CORES};
 use crate::debug;
+use crate::services::scheduler;
 use crate::services::task::Task;
-use crate::sync::{NakedMutex, Spinlock};
+use crate::sync::{NakedMutex, Spinlock, WaitList};
+use alloc::boxed::Box;
 use alloc::collections::{BTreeMap, VecDeque};
 use alloc::string::String;
 use alloc::vec::Vec;
 
+// Only held for short lookups and queue changes. A `Spinlock` masks interrupts on this core while held, so the holder
+// can't be preempted by a task that then spins on it, and it can be taken from interrupt handlers.
 pub(crate) static POST_OFFICE: Spinlock<RefCell<Option<PostOffice>>> =
     Spinlock::new(RefCell::new(None));
 
-#[derive(Debug)]
+// Messages posted by interrupt handlers, moved into mailboxes the next time any task receives
+static ISR_QUEUES: [IsrQueue; NUM_CORES] = [IsrQueue::new(), IsrQueue::new()];
+
+#[derive(Debug, defmt::Format)]
 pub enum PostOfficeError {
     AlreadyInitialized,
     NotInitialized,
     MailboxNotFound,
     MailboxTaskNameAlreadyUsed,
+    /// Blocking receives are only possible from a task, which receives from its own mailbox
+    NotCalledFromTask,
+    /// The mailbox is at capacity and its `SendPolicy` is `Fail`, or it is `Block` and the sender can't block
+    MailboxFull,
+    /// `call` gave up before the reply arrived
+    TimedOut,
+    /// `MailboxMessageType::Any` can't be copied for each subscriber of a topic
+    NotPublishable,
+    /// `post_from_isr` was handed more than `ISR_MESSAGE_SIZE` bytes
+    MessageTooLong,
+}
+
+/// What sending to a full mailbox does, chosen per mailbox with `Task::with_send_policy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum SendPolicy {
+    /// Parks the sending task until the receiver takes a message out. Senders that aren't tasks get `MailboxFull`.
+    Block,
+    /// Returns `PostOfficeError::MailboxFull` straight away
+    Fail,
+    /// Throws away the oldest queued message to make room
+    DropOldest,
+}
+
+// Result of trying to put a message in a mailbox
+enum Delivery {
+    Done(Result<(), PostOfficeError>),
+    // The mailbox is full and the sender has been added to its blocked senders, the message is handed back
+    Wait(MailboxMessage),
 }
 
 pub struct PostOffice {
     mailboxes: BTreeMap<usize, Mailboxes>,
     name_to_idx: BTreeMap<String, usize>,
+    // Task indexes subscribed to each topic
+    topics: BTreeMap<String, Vec<usize>>,
+    next_message_id: Cell<u32>,
+    // Messages from interrupt handlers dropped when drained, their mailbox was full or had been unregistered
+    dropped_isr_messages: Cell<u32>,
 }
 
 impl PostOffice {
@@ -35,6 +77,36 @@ impl PostOffice {
         PostOffice {
             mailboxes: BTreeMap::new(),
             name_to_idx: BTreeMap::new(),
+            topics: BTreeMap::new(),
+            next_message_id: Cell::new(0),
+            dropped_isr_messages: Cell::new(0),
+        }
+    }
+
+    fn task_idx(&self, task_name: &str) -> Result<usize, PostOfficeError> {
+        self.name_to_idx
+            .get(task_name)
+            .copied()
+            .ok_or(PostOfficeError::MailboxNotFound)
+    }
+
+    // Addresses a message from the calling task (None from an interrupt or `main`), each one gets a new ID that a
+    // reply can refer back to
+    fn new_message(
+        &self,
+        to_task: usize,
+        data: MailboxMessageType,
+        reply_to: Option<u32>,
+    ) -> MailboxMessage {
+        let id = self.next_message_id.get();
+        self.next_message_id.set(id.wrapping_add(1));
+
+        MailboxMessage {
+            id,
+            to_task,
+            from_task: scheduler::current_task_id(),
+            reply_to,
+            data,
         }
     }
 
@@ -54,33 +126,97 @@ impl PostOffice {
     pub(crate) fn register_mailbox(
         task_index: usize,
         task_name: &str,
+        capacity: usize,
+        policy: SendPolicy,
     ) -> Result<(), PostOfficeError> {
         if let Some(post_office) = POST_OFFICE.lock().borrow_mut().as_mut() {
-            let task_string = String::from(task_name);
+            // Checked before anything is inserted, a rejected name leaves the post office untouched
+            if post_office.name_to_idx.contains_key(task_name) {
+                return Err(PostOfficeError::MailboxTaskNameAlreadyUsed);
+            }
+
+            post_office
+                .mailboxes
+                .insert(task_index, Mailboxes::new(capacity, policy));
+            post_office
+                .name_to_idx
+                .insert(String::from(task_name), task_index);
+
+            debug!("New mailbox registered : {} | {}", task_index, task_name);
+            Ok(())
+        } else {
+            Err(PostOfficeError::NotInitialized)
+        }
+    }
 
-            post_office.mailboxes.insert(task_index, Mailboxes::new());
-            if post_office.name_to_idx.get(&task_string).is_none() {
-                post_office
-                    .name_to_idx
-                    .insert(String::from(task_name), task_index);
+    pub(crate) fn unregister_mailbox(task_index: usize) -> Result<(), PostOfficeError> {
+        if let Some(post_office) = POST_OFFICE.lock().borrow_mut().as_mut() {
+            if post_office.mailboxes.remove(&task_index).is_some() {
+                post_office.name_to_idx.retain(|_, idx| *idx != task_index);
+                post_office.topics.retain(|_, subscribers| {
+                    subscribers.retain(|idx| *idx != task_index);
+                    !subscribers.is_empty()
+                });
 
-                debug!("New mailbox registered : {} | {}", task_index, task_name);
+                debug!("Mailbox unregistered : {}", task_index);
                 Ok(())
             } else {
-                Err(PostOfficeError::MailboxTaskNameAlreadyUsed)
+                Err(PostOfficeError::MailboxNotFound)
             }
         } else {
             Err(PostOfficeError::NotInitialized)
         }
     }
 
-    pub fn send(&self, msg: MailboxMessage) -> Result<(), PostOfficeError> {
-        if let Some(mailboxes) = self.mailboxes.get(&msg.to_task) {
-            mailboxes.incoming.borrow_mut().push_front(msg);
+    /// Looks up the task index registered under `task_name`
+    pub fn get_task_id(task_name: &str) -> Result<usize, PostOfficeError> {
+        if let Some(post_office) = POST_OFFICE.lock().borrow().as_ref() {
+            post_office.task_idx(task_name)
+        } else {
+            Err(PostOfficeError::NotInitialized)
+        }
+    }
 
-            Ok(())
+    /// Sends `data` to the mailbox of the task with index `to_task`
+    pub fn send(to_task: usize, data: MailboxMessageType) -> Result<(), PostOfficeError> {
+        let msg = if let Some(post_office) = POST_OFFICE.lock().borrow().as_ref() {
+            post_office.new_message(to_task, data, None)
         } else {
-            Err(PostOfficeError::MailboxNotFound)
+            return Err(PostOfficeError::NotInitialized);
+        };
+
+        PostOffice::post(msg)
+    }
+
+    // Delivers `msg`, blocking the calling task while the mailbox is full if its policy says to
+    fn post(mut msg: MailboxMessage) -> Result<(), PostOfficeError> {
+        let sender = scheduler::current_task_id();
+
+        loop {
+            let mut woken = Vec::new();
+            let post_office = POST_OFFICE.lock();
+            let delivery = match post_office.borrow().as_ref() {
+                Some(post_office) => match post_office.mailboxes.get(&msg.to_task) {
+                    Some(mailboxes) => mailboxes.deliver(msg, sender, &mut woken),
+                    None => Delivery::Done(Err(PostOfficeError::MailboxNotFound)),
+                },
+                None => Delivery::Done(Err(PostOfficeError::NotInitialized)),
+            };
+
+            match delivery {
+                Delivery::Done(result) => {
+                    drop(post_office);
+                    scheduler::wake_tasks(&woken);
+                    return result;
+                }
+                Delivery::Wait(returned) => {
+                    msg = returned;
+                    // Room is only made under the post office, so being blocked before letting go of it means the
+                    // receiver's wake can't be missed
+                    scheduler::block_and_release(None, post_office, || false);
+                    scheduler::yield_now();
+                }
+            }
         }
     }
 
@@ -88,74 +224,440 @@ impl PostOffice {
         task_name: &str,
         data: MailboxMessageType,
     ) -> Result<(), PostOfficeError> {
-        if let Some(post_office) = POST_OFFICE.lock().borrow().as_ref() {
-            let msg = MailboxMessage {
-                to_task: *post_office.name_to_idx.get(task_name).unwrap(),
-                from_task: 0,
-                data: data,
-            };
+        let msg = if let Some(post_office) = POST_OFFICE.lock().borrow().as_ref() {
+            post_office.new_message(post_office.task_idx(task_name)?, data, None)
+        } else {
+            return Err(PostOfficeError::NotInitialized);
+        };
 
-            if let Some(mailboxes) = post_office.mailboxes.get(&msg.to_task) {
-                mailboxes.incoming.borrow_mut().push_front(msg);
+        PostOffice::post(msg)
+    }
 
-                Ok(())
-            } else {
-                Err(PostOfficeError::MailboxNotFound)
+    /// Subscribes the calling task to `topic`, everything published to it is delivered to the task's mailbox
+    pub fn subscribe(topic: &str) -> Result<(), PostOfficeError> {
+        let task_idx = scheduler::current_task_id().ok_or(PostOfficeError::NotCalledFromTask)?;
+
+        if let Some(post_office) = POST_OFFICE.lock().borrow_mut().as_mut() {
+            let subscribers = post_office.topics.entry(String::from(topic)).or_default();
+            if !subscribers.contains(&task_idx) {
+                subscribers.push(task_idx);
             }
+
+            Ok(())
         } else {
             Err(PostOfficeError::NotInitialized)
         }
     }
 
-    pub fn recv(task_idx: usize) -> Result<Option<MailboxMessage>, PostOfficeError> {
-        if let Some(post_office) = POST_OFFICE.lock().borrow().as_ref() {
-            if let Some(mailboxes) = post_office.mailboxes.get(&task_idx) {
-                Ok(mailboxes.incoming.borrow_mut().pop_front())
-            } else {
-                Err(PostOfficeError::MailboxNotFound)
+    pub fn unsubscribe(topic: &str) -> Result<(), PostOfficeError> {
+        let task_idx = scheduler::current_task_id().ok_or(PostOfficeError::NotCalledFromTask)?;
+
+        if let Some(post_office) = POST_OFFICE.lock().borrow_mut().as_mut() {
+            if let Some(subscribers) = post_office.topics.get_mut(topic) {
+                subscribers.retain(|idx| *idx != task_idx);
+                if subscribers.is_empty() {
+                    post_office.topics.remove(topic);
+                }
             }
+
+            Ok(())
         } else {
             Err(PostOfficeError::NotInitialized)
         }
     }
 
-    pub fn recv_by_name(task_name: String) -> Result<Option<MailboxMessage>, PostOfficeError> {
+    /// Sends a copy of `data` to every task subscribed to `topic` and returns how many it was delivered to. Each
+    /// subscriber's `SendPolicy` applies, one that can't take the message doesn't stop the rest getting it.
+    pub fn publish(topic: &str, data: MailboxMessageType) -> Result<usize, PostOfficeError> {
+        let messages = if let Some(post_office) = POST_OFFICE.lock().borrow().as_ref() {
+            match post_office.topics.get(topic) {
+                Some(subscribers) => subscribers
+                    .iter()
+                    .map(|idx| Ok(post_office.new_message(*idx, data.try_clone()?, None)))
+                    .collect::<Result<Vec<_>, PostOfficeError>>()?,
+                None => Vec::new(),
+            }
+        } else {
+            return Err(PostOfficeError::NotInitialized);
+        };
+
+        // Posted one at a time without holding the post office, a subscriber may block the publisher
+        Ok(messages
+            .into_iter()
+            .map(PostOffice::post)
+            .filter(Result::is_ok)
+            .count())
+    }
+
+    /// Queues `data` for the mailbox of `task_idx` without allocating or taking the post office, so interrupt handlers
+    /// can send messages. The task is woken and gets a `MailboxMessageType::Interrupt` on its next receive. Fails
+    /// with `MailboxFull` while this core's queue has no free slot. The mailbox is only looked up when the receiver
+    /// next receives, a message whose mailbox is full or doesn't exist by then is dropped and counted in
+    /// `dropped_isr_messages`.
+    pub fn post_from_isr(task_idx: usize, data: &[u8]) -> Result<(), PostOfficeError> {
+        if data.len() > ISR_MESSAGE_SIZE {
+            return Err(PostOfficeError::MessageTooLong);
+        }
+
+        let mut msg = IsrMessage {
+            to_task: task_idx,
+            len: data.len(),
+            data: [0; ISR_MESSAGE_SIZE],
+        };
+        msg.data[..data.len()].copy_from_slice(data);
+
+        if !ISR_QUEUES[scheduler::core_id()].push(msg) {
+            return Err(PostOfficeError::MailboxFull);
+        }
+
+        // Pends PendSV if the receiver should run straight away, or rings the other core if it lives there
+        scheduler::wake_task(task_idx);
+        Ok(())
+    }
+
+    // Moves everything interrupt handlers have posted into the mailboxes, the caller holds the post office so it is
+    // the only one taking from the queues. Nothing can wait here, so messages to a full mailbox (or one unregistered
+    // since they were posted) are dropped and counted.
+    fn drain_isr_queues(&self, woken: &mut Vec<usize>) {
+        for queue in ISR_QUEUES.iter() {
+            while let Some(isr_msg) = queue.pop() {
+                let delivered = match self.mailboxes.get(&isr_msg.to_task) {
+                    Some(mailboxes) => {
+                        let mut msg = self.new_message(
+                            isr_msg.to_task,
+                            MailboxMessageType::Interrupt(isr_msg.data[..isr_msg.len].to_vec()),
+                            None,
+                        );
+                        // Sent by an interrupt handler, not the task draining the queue
+                        msg.from_task = None;
+
+                        matches!(mailboxes.deliver(msg, None, woken), Delivery::Done(Ok(())))
+                    }
+                    None => false,
+                };
+
+                if !delivered {
+                    self.dropped_isr_messages
+                        .set(self.dropped_isr_messages.get().wrapping_add(1));
+                    debug!("Interrupt message to {} dropped", isr_msg.to_task);
+                }
+            }
+        }
+    }
+
+    /// How many messages from `post_from_isr` have been dropped because their mailbox was full or gone
+    pub fn dropped_isr_messages() -> Result<u32, PostOfficeError> {
         if let Some(post_office) = POST_OFFICE.lock().borrow().as_ref() {
-            if let Some(mailboxes) = post_office
-                .mailboxes
-                .get(&post_office.name_to_idx.get(&task_name).unwrap())
-            {
-                Ok(mailboxes.incoming.borrow_mut().pop_front())
+            Ok(post_office.dropped_isr_messages.get())
+        } else {
+            Err(PostOfficeError::NotInitialized)
+        }
+    }
+
+    pub fn recv(task_idx: usize) -> Result<Option<MailboxMessage>, PostOfficeError> {
+        let mut woken = Vec::new();
+        let msg = if let Some(post_office) = POST_OFFICE.lock().borrow().as_ref() {
+            post_office.drain_isr_queues(&mut woken);
+            if let Some(mailboxes) = post_office.mailboxes.get(&task_idx) {
+                Ok(mailboxes.take(&mut woken))
             } else {
                 Err(PostOfficeError::MailboxNotFound)
             }
         } else {
             Err(PostOfficeError::NotInitialized)
+        };
+
+        // Only once the post office is released, a woken task that preempts us would otherwise spin on it
+        scheduler::wake_tasks(&woken);
+        msg
+    }
+
+    /// Receives from the calling task's own mailbox, parking the task until a message arrives. Gives up after
+    /// `timeout_us` microseconds (None waits forever) and returns `Ok(None)` if nothing came.
+    pub fn recv_blocking(
+        timeout_us: Option<u32>,
+    ) -> Result<Option<MailboxMessage>, PostOfficeError> {
+        let task_idx = scheduler::current_task_id().ok_or(PostOfficeError::NotCalledFromTask)?;
+        let deadline = timeout_us.map(|timeout_us| scheduler::now_us().wrapping_add(timeout_us));
+
+        PostOffice::recv_matching(task_idx, deadline, |_| true)
+    }
+
+    /// Sends `data` to the task registered under `task_name` and waits for it to `reply`, giving up after
+    /// `timeout_us` microseconds (None waits forever). Other messages that arrive in the meantime stay queued, so the
+    /// caller's mailbox needs room left for the reply.
+    pub fn call(
+        task_name: &str,
+        data: MailboxMessageType,
+        timeout_us: Option<u32>,
+    ) -> Result<MailboxMessage, PostOfficeError> {
+        let task_idx = scheduler::current_task_id().ok_or(PostOfficeError::NotCalledFromTask)?;
+        let deadline = timeout_us.map(|timeout_us| scheduler::now_us().wrapping_add(timeout_us));
+
+        let request = if let Some(post_office) = POST_OFFICE.lock().borrow().as_ref() {
+            post_office.new_message(post_office.task_idx(task_name)?, data, None)
+        } else {
+            return Err(PostOfficeError::NotInitialized);
+        };
+        let request_id = request.id;
+        PostOffice::post(request)?;
+
+        PostOffice::recv_matching(task_idx, deadline, |msg| msg.reply_to == Some(request_id))?
+            .ok_or(PostOfficeError::TimedOut)
+    }
+
+    /// Answers `request` with `data`, waking its sender if it is waiting in `call`
+    pub fn reply(
+        request: &MailboxMessage,
+        data: MailboxMessageType,
+    ) -> Result<(), PostOfficeError> {
+        let to_task = request.from_task.ok_or(PostOfficeError::MailboxNotFound)?;
+
+        let msg = if let Some(post_office) = POST_OFFICE.lock().borrow().as_ref() {
+            post_office.new_message(to_task, data, Some(request.id))
+        } else {
+            return Err(PostOfficeError::NotInitialized);
+        };
+
+        PostOffice::post(msg)
+    }
+
+    // Takes the oldest message in `task_idx`'s mailbox that `matches`, blocking the caller until one arrives or the
+    // timer reaches `deadline`
+    fn recv_matching(
+        task_idx: usize,
+        deadline: Option<u32>,
+        matches: impl Fn(&MailboxMessage) -> bool,
+    ) -> Result<Option<MailboxMessage>, PostOfficeError> {
+        loop {
+            let mut woken = Vec::new();
+            let post_office = POST_OFFICE.lock();
+            let msg = match post_office.borrow().as_ref() {
+                Some(post_office) => {
+                    post_office.drain_isr_queues(&mut woken);
+                    post_office
+                        .mailboxes
+                        .get(&task_idx)
+                        .map(|mailboxes| mailboxes.take_matching(&matches, &mut woken))
+                        .ok_or(PostOfficeError::MailboxNotFound)
+                }
+                None => Err(PostOfficeError::NotInitialized),
+            };
+
+            let timed_out = deadline.map_or(false, |deadline| {
+                scheduler::deadline_reached(scheduler::now_us(), deadline)
+            });
+            if !matches!(msg, Ok(None)) || timed_out {
+                drop(post_office);
+                scheduler::wake_tasks(&woken);
+                return msg;
+            }
+
+            // Senders deliver under the post office and only wake us after, so being blocked before letting go of it
+            // can't lose a message. An interrupt handler posting in the meantime keeps us from blocking so the next
+            // pass can collect it.
+            scheduler::block_and_release(deadline, post_office, || {
+                ISR_QUEUES.iter().any(|queue| !queue.is_empty())
+            });
+            scheduler::wake_tasks(&woken);
+            scheduler::yield_now();
         }
     }
+
+    pub fn recv_by_name(task_name: String) -> Result<Option<MailboxMessage>, PostOfficeError> {
+        PostOffice::recv(PostOffice::get_task_id(&task_name)?)
+    }
 }
 
 #[derive(Debug)]
 pub struct MailboxMessage {
+    id: u32,
     to_task: usize,
-    from_task: usize,
+    from_task: Option<usize>,
+    // ID of the request this answers, set by `PostOffice::reply`
+    reply_to: Option<u32>,
     pub data: MailboxMessageType,
 }
 
+impl MailboxMessage {
+    pub fn get_id(&self) -> u32 {
+        self.id
+    }
+
+    /// Task index of the mailbox the message was sent to
+    pub fn get_to_task(&self) -> usize {
+        self.to_task
+    }
+
+    /// Task index of the sender, None if it was sent from an interrupt or `main`
+    pub fn get_from_task(&self) -> Option<usize> {
+        self.from_task
+    }
+
+    /// ID of the request this message is a reply to
+    pub fn get_reply_to(&self) -> Option<u32> {
+        self.reply_to
+    }
+}
+
 #[derive(Debug)]
 pub enum MailboxMessageType {
     Generic(Vec<u8>),
     Uart(Vec<u8>),
+    /// Bytes an interrupt handler sent with `PostOffice::post_from_isr`
+    Interrupt(Vec<u8>),
+    /// Any other message, the receiver downcasts it back to the type it expects. Tasks that only talk to each other
+    /// can use a typed `channel` instead of going through the post office.
+    Any(Box<dyn Any + Send>),
+}
+
+impl MailboxMessageType {
+    // Copies the message for each subscriber of a topic
+    fn try_clone(&self) -> Result<Self, PostOfficeError> {
+        match self {
+            MailboxMessageType::Generic(data) => Ok(MailboxMessageType::Generic(data.clone())),
+            MailboxMessageType::Uart(data) => Ok(MailboxMessageType::Uart(data.clone())),
+            MailboxMessageType::Interrupt(data) => Ok(MailboxMessageType::Interrupt(data.clone())),
+            MailboxMessageType::Any(_) => Err(PostOfficeError::NotPublishable),
+        }
+    }
 }
 
 pub struct Mailboxes {
     incoming: RefCell<VecDeque<MailboxMessage>>,
+    capacity: usize,
+    policy: SendPolicy,
+    // Tasks blocked sending to this mailbox while it is full
+    blocked_senders: RefCell<WaitList>,
 }
 
 impl Mailboxes {
-    fn new() -> Self {
+    fn new(capacity: usize, policy: SendPolicy) -> Self {
+        Self {
+            incoming: RefCell::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            policy,
+            blocked_senders: RefCell::new(WaitList::new()),
+        }
+    }
+
+    // Queues `msg` behind anything already waiting. The owning task may be blocked in `recv_blocking` so it is added to
+    // `woken`, for the caller to wake once it has let go of the post office.
+    fn deliver(
+        &self,
+        msg: MailboxMessage,
+        sender: Option<usize>,
+        woken: &mut Vec<usize>,
+    ) -> Delivery {
+        let mut incoming = self.incoming.borrow_mut();
+
+        if incoming.len() >= self.capacity {
+            match (self.policy, sender) {
+                (SendPolicy::Block, Some(sender)) => {
+                    self.blocked_senders.borrow_mut().add(sender);
+                    return Delivery::Wait(msg);
+                }
+                (SendPolicy::DropOldest, _) => {
+                    incoming.pop_front();
+                }
+                _ => return Delivery::Done(Err(PostOfficeError::MailboxFull)),
+            }
+        }
+
+        // A mailbox belongs to the task with the same index
+        woken.push(msg.to_task);
+        incoming.push_back(msg);
+        Delivery::Done(Ok(()))
+    }
+
+    fn take(&self, woken: &mut Vec<usize>) -> Option<MailboxMessage> {
+        self.take_matching(|_| true, woken)
+    }
+
+    // Takes the oldest message that `matches`, adding every blocked sender to `woken` so they try again with the room
+    // that was made
+    fn take_matching(
+        &self,
+        matches: impl Fn(&MailboxMessage) -> bool,
+        woken: &mut Vec<usize>,
+    ) -> Option<MailboxMessage> {
+        let mut incoming = self.incoming.borrow_mut();
+        let position = incoming.iter().position(matches);
+        let msg = position.and_then(|idx| incoming.remove(idx));
+
+        if msg.is_some() {
+            let mut blocked_senders = self.blocked_senders.borrow_mut();
+            while let Some(sender) = blocked_senders.take_first() {
+                woken.push(sender);
+            }
+        }
+        msg
+    }
+}
+
+#[derive(Clone, Copy)]
+struct IsrMessage {
+    to_task: usize,
+    len: usize,
+    data: [u8; ISR_MESSAGE_SIZE],
+}
+
+// Ring of messages from the interrupt handlers of one core. The M0+ has no compare and swap, so the handlers filling it
+// mask interrupts to keep a nested handler from racing them and the post office holder is the only one emptying it.
+// Each side only ever stores its own index, which is all the two sides need to agree on which slots are in use.
+struct IsrQueue {
+    slots: UnsafeCell<[IsrMessage; ISR_QUEUE_SIZE]>,
+    // Next slot to fill, only stored by this core's interrupt handlers
+    head: AtomicUsize,
+    // Next slot to take, only stored with the post office held
+    tail: AtomicUsize,
+}
+
+unsafe impl Sync for IsrQueue {}
+
+impl IsrQueue {
+    const fn new() -> Self {
         Self {
-            incoming: RefCell::new(VecDeque::new()),
+            slots: UnsafeCell::new(
+                [IsrMessage {
+                    to_task: 0,
+                    len: 0,
+                    data: [0; ISR_MESSAGE_SIZE],
+                }; ISR_QUEUE_SIZE],
+            ),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
         }
     }
+
+    // One slot is always left empty so a full queue can be told apart from an empty one
+    fn push(&self, msg: IsrMessage) -> bool {
+        cortex_m::interrupt::free(|_| {
+            let head = self.head.load(Ordering::Relaxed);
+            let next = (head + 1) % ISR_QUEUE_SIZE;
+            if next == self.tail.load(Ordering::Acquire) {
+                return false;
+            }
+
+            unsafe { (*self.slots.get())[head] = msg };
+            self.head.store(next, Ordering::Release);
+            true
+        })
+    }
+
+    fn pop(&self) -> Option<IsrMessage> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail == self.head.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let msg = unsafe { (*self.slots.get())[tail] };
+        self.tail
+            .store((tail + 1) % ISR_QUEUE_SIZE, Ordering::Release);
+        Some(msg)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tail.load(Ordering::Acquire) == self.head.load(Ordering::Acquire)
+    }
 }
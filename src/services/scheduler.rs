@@ -1,168 +1,1449 @@
 use alloc;
-use core::{arch::asm, cell::RefCell};
-use cortex_m::peripheral::{syst::SystClkSource, SYST};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::{
+    any::Any,
+    arch::asm,
+    cell::{Cell, RefCell},
+    ops::Bound,
+};
+use cortex_m::peripheral::{
+    scb::{SystemHandler, VectActive},
+    syst::SystClkSource,
+    NVIC, SCB, SYST,
+};
 use cortex_m_rt::exception;
+use embedded_time::{duration::Microseconds, fixed_point::FixedPoint, rate::Hertz};
 
-use crate::constants::MAX_TASKS;
+use crate::bsp::hal::sio::SioFifo;
+use crate::constants::{CORE1_STACK_SIZE, MAX_TASKS, NUM_CORES};
 use crate::debug;
-use crate::services::task::Task;
-use crate::sync::NakedMutex;
+use crate::pac::{self, interrupt, Interrupt};
+use crate::services::task::{Task, TaskArgument, TaskState, TaskStats};
+use crate::sync::{self, MutexState, NakedMutex, WaitList, SCHEDULER_SPINLOCK};
+use crate::task;
 
-use super::post_office::PostOffice;
+use super::post_office::{PostOffice, PostOfficeError};
 
-static SCHEDULER: NakedMutex<RefCell<Option<Scheduler>>> = NakedMutex::new(RefCell::new(None));
+// One scheduler per core, indexed by core number. Either core can act on the other's tasks so both sit behind
+// the same hardware spinlock, see `with_schedulers`.
+static SCHEDULERS: NakedMutex<RefCell<[Option<Scheduler>; NUM_CORES]>> =
+    NakedMutex::new(RefCell::new([None, None]));
+
+// Task IDs are handed out across both cores so they stay unique as mailbox indexes in the `PostOffice`
+static NEXT_TASK_ID: NakedMutex<Cell<usize>> = NakedMutex::new(Cell::new(0));
+
+// `launch_core1` leaves core 1's scheduler here for core 1 to pick up once it is running
+static CORE1_LAUNCH: NakedMutex<RefCell<Option<(Scheduler, Hertz<u32>)>>> =
+    NakedMutex::new(RefCell::new(None));
+
+// Its end is core 1's initial stack pointer, which the AAPCS requires to be 8 byte aligned
+#[repr(C, align(8))]
+struct Core1Stack([usize; CORE1_STACK_SIZE]);
+
+static mut CORE1_STACK: Core1Stack = Core1Stack([0; CORE1_STACK_SIZE]);
+
+// Bits of the SIO FIFO_ST register
+const FIFO_VALID: u32 = 1 << 0;
+const FIFO_READY: u32 = 1 << 1;
+
+// The built in idle task lives in the task table under an ID `add_task` can never hand out
+const IDLE_TASK_ID: usize = usize::MAX;
 
 #[exception]
-unsafe fn SysTick() {
-    // Push the full context onto the stack
-    cortex_m::interrupt::disable();
+fn SysTick() {
+    // The context switch and run time accounting are left to PendSV
+    SCB::set_pendsv();
+}
+
+// Being naked means the compiler emits no prologue or epilogue, so the stack layout is exactly what we push here
+// regardless of optimisation level. Tasks run on the main stack, so the hardware has already stacked
+// r0-r3, r12, lr, pc and xpsr onto the outgoing task's stack by the time we get here.
+#[naked]
+#[no_mangle]
+unsafe extern "C" fn PendSV() {
     asm!(
-        // This is the hackiest of hacks, I do not like it at all, but without abandoning Rust, or going to a naked function that can only have ASM, I don't know how else to approaach Rust emitting sporadic code.
-        // Rust is pushing 4 registers to the stack, then subtracting 8 more bytes for stack variables (that aren't used anywhere?)
-        "add sp, 0x18",
+        "cpsid i",
+        // Push the rest of the outgoing task's context
         "push {{r4-r7}}",
         "mov r4, r8",
         "mov r5, r9",
         "mov r6, r10",
         "mov r7, r11",
         "push {{r4-r7}}",
-    );
-
-    {
-        let mut borrow = SCHEDULER.borrow().borrow_mut();
-        let sched = borrow.as_mut().unwrap();
-
-        // // Finish saving this task's context by saving the stack pointer so we can find it again
-        let mut curr_sp: usize;
-        asm!("mov {0}, sp", out(reg) curr_sp);
-        sched.set_current_task_stack_pointer(curr_sp);
-
-        // Now we move the current task and load the context by getting the stack pointer it saved
-        sched.next_task();
-
-        let new_sp = sched.get_current_task_stack_pointer();
-        asm!("mov sp, {0}", in(reg) new_sp);
-    }
-
-    // // // Pop the full context off of the stack
-    asm!(
+        // Hand the outgoing stack pointer to the scheduler and get back the incoming task's
+        "mov r0, sp",
+        "bl {switch_context}",
+        "mov sp, r0",
+        // Pop the incoming task's context
         "pop {{r4-r7}}",
         "mov r8, r4",
         "mov r9, r5",
         "mov r10, r6",
         "mov r11, r7",
         "pop {{r4-r7}}",
+        "cpsie i",
+        // Return to thread mode on the main stack (EXC_RETURN 0xFFFFFFF9), r0 is restored from the hardware frame
+        "movs r0, #6",
+        "mvns r0, r0",
+        "bx r0",
+        switch_context = sym switch_context,
+        options(noreturn)
     );
-    cortex_m::interrupt::enable();
-    asm!("bx lr");
 }
 
+// Only armed in tickless mode while idling, they fire at the earliest sleeping task's deadline so the
+// scheduler can wake it. Each core uses the alarm matching its number, so the HAL's `Alarm0` and `Alarm1`
+// must not be used alongside this.
+#[interrupt]
+fn TIMER_IRQ_0() {
+    alarm_fired(0);
+}
+
+#[interrupt]
+fn TIMER_IRQ_1() {
+    alarm_fired(1);
+}
+
+fn alarm_fired(alarm: usize) {
+    unsafe {
+        (*pac::TIMER::ptr()).intr.write(|w| w.bits(1 << alarm));
+    }
+    SCB::set_pendsv();
+}
+
+// The other core writes to our FIFO after it changes one of our tasks, the words themselves mean nothing
+#[interrupt]
+fn SIO_IRQ_PROC0() {
+    doorbell_rung();
+}
+
+#[interrupt]
+fn SIO_IRQ_PROC1() {
+    doorbell_rung();
+}
+
+fn doorbell_rung() {
+    let sio = unsafe { &*pac::SIO::ptr() };
+    while sio.fifo_st.read().bits() & FIFO_VALID != 0 {
+        sio.fifo_rd.read();
+    }
+    // Overflow and underflow flags raise this interrupt too, writing clears them
+    sio.fifo_st.write(|w| unsafe { w.bits(0xFF) });
+
+    SCB::set_pendsv();
+}
+
+// Interrupts `core` so it reschedules, this is a no-op for our own core. A full FIFO means the other core already
+// has a wake pending so nothing is lost by skipping the write.
+fn notify_core(core: usize) {
+    if core == core_id() {
+        return;
+    }
+
+    let sio = unsafe { &*pac::SIO::ptr() };
+    if sio.fifo_st.read().bits() & FIFO_READY != 0 {
+        sio.fifo_wr.write(|w| unsafe { w.bits(0) });
+    }
+}
+
+// Core 1 starts here once the bootrom hands over, running on `CORE1_STACK` until its first context switch
+extern "C" fn core1_entry() -> ! {
+    let (scheduler, system_clock) = sync::cross_core_free(SCHEDULER_SPINLOCK, || {
+        CORE1_LAUNCH.borrow().borrow_mut().take()
+    })
+    .unwrap();
+
+    // Core 0 took the core peripherals, but SysTick and the SCB are private to each core so core 1 can have its own
+    let mut core = unsafe { pac::CorePeripherals::steal() };
+
+    // `launch_core1` already checked everything that could make this fail
+    scheduler
+        .start(core.SYST, &mut core.SCB, system_clock)
+        .unwrap();
+
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
+
+// Runs whenever no other task is ready. Interrupts are masked while checking so one that readies a task can't
+// slip in between the check and the `wfi`, a pending interrupt still wakes the core with them masked.
+#[task]
+fn idle() -> ! {
+    loop {
+        cortex_m::interrupt::free(|_| {
+            if !with_scheduler(|sched| sched.has_ready_task()) {
+                cortex_m::asm::wfi();
+            }
+        });
+        yield_now();
+    }
+}
+
+// Called from PendSV with interrupts disabled, takes the outgoing task's stack pointer and returns the incoming one's
+extern "C" fn switch_context(sp: usize) -> usize {
+    with_scheduler(|sched| {
+        // Finish saving this task's context by saving the stack pointer so we can find it again
+        sched.set_current_task_stack_pointer(sp);
+        sched.check_current_task_stack();
+
+        // Now we move the current task and load the context by getting the stack pointer it saved
+        sched.next_task();
+        sched.get_current_task_stack_pointer()
+    })
+}
+
+/// The contained duration is the length of a time slice, it is converted to SysTick cycles in `Scheduler::start`
 pub enum ScheduleType {
-    RoundRobin(u32),
+    RoundRobin(Microseconds<u32>),
+    /// Always runs the highest priority task, tasks sharing a priority are round robined every interval
+    Priority(Microseconds<u32>),
+    /// Runs the ready periodic task whose job has the nearest absolute deadline, tasks without a period are only
+    /// round robined when no periodic task is ready
+    EarliestDeadlineFirst(Microseconds<u32>),
+    /// Runs the ready periodic task with the shortest period, tasks without a period are only round robined when no
+    /// periodic task is ready
+    RateMonotonic(Microseconds<u32>),
 }
 
 #[derive(Debug)]
 pub enum SchedulerError {
     NoPopulatedTasks,
     TaskListFull,
+    TaskNotFound,
+    JoinTypeMismatch,
+    /// The time slice doesn't fit in SysTick's 24 bit reload register at the current system clock
+    InvalidTimeSlice,
+    /// A task is pinned to a different core than the scheduler is starting on
+    WrongCore,
+    /// The core a task is pinned to has no running scheduler
+    CoreNotRunning,
+    /// Only a task can wait, this was called from an interrupt handler or before the scheduler started
+    NotCalledFromTask,
+    /// The task's mailbox couldn't be registered, usually because another task already has its name
+    PostOffice(PostOfficeError),
+}
+
+/// Snapshot of every task's statistics, a task's share of the CPU is its `TaskStats::run_time_us` over ours. Run time
+/// is measured with the timer at every context switch, so it stays accurate while tickless idle has SysTick stopped.
+#[derive(Debug, Clone)]
+pub struct SchedulerStats {
+    pub core: usize,
+    /// Microseconds run by all tasks on this core, the idle task included
+    pub run_time_us: u64,
+    pub tasks: Vec<TaskStats>,
+}
+
+impl defmt::Format for SchedulerStats {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "core {} | ran {}us", self.core, self.run_time_us);
+        for task in self.tasks.iter() {
+            defmt::write!(f, "\n{}", task);
+        }
+    }
 }
 
 pub struct Scheduler {
     schedule_type: ScheduleType,
-    current_task_idx: Option<usize>,
-    populated_tasks: usize,
-    tasks: [Option<Task>; MAX_TASKS],
+    current_task_id: Option<usize>,
+    // Set by `start` to the core this scheduler runs on
+    core: usize,
+    // Keyed by task ID, IDs are never reused so they stay valid as mailbox indexes in the `PostOffice`
+    tasks: BTreeMap<usize, Task>,
+    // Return values of exited tasks waiting to be collected by `join`
+    results: BTreeMap<usize, Box<dyn Any + Send>>,
+    run_time_us: u64,
+    // Timer reading at the last context switch, the outgoing task is charged for the time since
+    last_switch: u32,
+    tickless: bool,
+    // Held after `start` so tickless mode can stop and restart the tick around idle periods
+    systick: Option<SYST>,
+    tick_stopped: bool,
 }
 
 impl Scheduler {
     pub fn new(schedule_type: ScheduleType) -> Self {
+        let mut tasks = BTreeMap::new();
+        tasks.insert(IDLE_TASK_ID, Task::new("Idle", idle, _idleArguments {}));
+
         Self {
             schedule_type,
-            current_task_idx: None,
-            populated_tasks: 0,
-            // tasks: [None; MAX_TASKS],
-            tasks: [None, None, None, None],
+            current_task_id: None,
+            core: 0,
+            tasks,
+            results: BTreeMap::new(),
+            run_time_us: 0,
+            last_switch: 0,
+            tickless: false,
+            systick: None,
+            tick_stopped: false,
         }
     }
 
+    /// Stops SysTick while the idle task runs and wakes up from a timer alarm at the earliest sleeping task's
+    /// deadline instead, so the core stays in `wfi` until there is something to do
+    pub fn with_tickless_idle(mut self) -> Self {
+        self.tickless = true;
+        self
+    }
+
     fn next_task(&mut self) {
-        match self.schedule_type {
-            ScheduleType::RoundRobin(_) => match self.current_task_idx {
-                Some(idx) => {
-                    if idx + 1 == self.populated_tasks {
-                        self.current_task_idx = Some(0);
-                    } else {
-                        self.current_task_idx = Some(idx + 1);
+        let now = now_us();
+        let elapsed = now.wrapping_sub(self.last_switch);
+        self.last_switch = now;
+        // Nothing has run before the first switch
+        if let Some(task) = self.get_current_task_mut() {
+            task.record_run_time(elapsed);
+        }
+        if self.current_task_id.is_some() {
+            self.run_time_us += elapsed as u64;
+        }
+
+        self.reap_exited_tasks();
+        self.wake_sleeping_tasks(now);
+
+        // The outgoing task is only put back in the ready queue if it didn't block or go to sleep
+        if let Some(task) = self.get_current_task_mut() {
+            if task.get_state() == TaskState::Running {
+                task.set_state(TaskState::Ready);
+            }
+        }
+
+        let next = match self.schedule_type {
+            ScheduleType::RoundRobin(_) => self.find_ready_task(|_| true),
+            ScheduleType::Priority(_) => {
+                let highest = self
+                    .ready_tasks()
+                    .map(|(_, task)| task.get_priority())
+                    .max();
+
+                self.find_ready_task(|task| Some(task.get_priority()) == highest)
+            }
+            ScheduleType::EarliestDeadlineFirst(_) => self
+                .ready_tasks()
+                .filter_map(|(id, task)| Some((id, task.get_absolute_deadline()?)))
+                // Signed so a job that is already overdue sorts ahead of ones still on time
+                .min_by_key(|(_, deadline)| deadline.wrapping_sub(now) as i32)
+                .map(|(id, _)| id)
+                .or_else(|| self.find_ready_task(|_| true)),
+            ScheduleType::RateMonotonic(_) => {
+                let shortest = self
+                    .ready_tasks()
+                    .filter_map(|(_, task)| task.get_period())
+                    .min();
+
+                match shortest {
+                    Some(period) => self.find_ready_task(|task| task.get_period() == Some(period)),
+                    None => self.find_ready_task(|_| true),
+                }
+            }
+        };
+
+        // The idle task only runs when nothing else is ready
+        let id = next.unwrap_or(IDLE_TASK_ID);
+        self.current_task_id = Some(id);
+        let task = self.get_current_task_mut().unwrap();
+        task.set_state(TaskState::Running);
+        task.record_scheduled();
+
+        if self.tickless {
+            self.update_tick(id == IDLE_TASK_ID);
+        }
+    }
+
+    // Stops the tick and arms this core's timer alarm for the earliest sleeper when going idle, restarts it otherwise
+    fn update_tick(&mut self, idling: bool) {
+        let timer = unsafe { &*pac::TIMER::ptr() };
+        let alarm_bit = 1 << self.core;
+        let systick = self.systick.as_mut().unwrap();
+
+        if idling {
+            if !self.tick_stopped {
+                systick.disable_interrupt();
+                systick.disable_counter();
+                self.tick_stopped = true;
+            }
+
+            let now = now_us();
+            let earliest_deadline = self
+                .tasks
+                .values()
+                .filter_map(|task| match task.get_state() {
+                    TaskState::Sleeping(deadline) | TaskState::BlockedUntil(deadline) => {
+                        Some(deadline)
+                    }
+                    _ => None,
+                })
+                .min_by_key(|deadline| deadline.wrapping_sub(now));
+
+            match earliest_deadline {
+                Some(deadline) => {
+                    match self.core {
+                        0 => timer.alarm0.write(|w| unsafe { w.bits(deadline) }),
+                        _ => timer.alarm1.write(|w| unsafe { w.bits(deadline) }),
+                    }
+                    // The alarm only fires on an exact match, so if the deadline slipped by while arming it we
+                    // have to switch again ourselves
+                    if deadline_reached(now_us(), deadline) {
+                        SCB::set_pendsv();
                     }
                 }
-                None => self.current_task_idx = Some(0),
-            },
+                // Nothing is sleeping, only an interrupt readying a task can wake us
+                None => timer.armed.write(|w| unsafe { w.bits(alarm_bit) }),
+            }
+        } else {
+            timer.armed.write(|w| unsafe { w.bits(alarm_bit) });
+
+            if self.tick_stopped {
+                systick.clear_current();
+                systick.enable_counter();
+                systick.enable_interrupt();
+                self.tick_stopped = false;
+            }
         }
     }
 
-    // If `current_task_idx` is None (only on first trigger of SysTick) we just ignore the store
+    fn has_ready_task(&self) -> bool {
+        self.tasks
+            .values()
+            .any(|task| task.get_state() == TaskState::Ready)
+    }
+
+    fn ready_tasks(&self) -> impl Iterator<Item = (usize, &Task)> {
+        self.tasks
+            .iter()
+            .filter(|&(id, task)| *id != IDLE_TASK_ID && task.get_state() == TaskState::Ready)
+            .map(|(id, task)| (*id, task))
+    }
+
+    // Searches for a ready task starting after the current task so tasks that match equally take turns
+    fn find_ready_task(&self, filter: impl Fn(&Task) -> bool) -> Option<usize> {
+        let after_current = match self.current_task_id {
+            Some(id) => Bound::Excluded(id),
+            None => Bound::Unbounded,
+        };
+
+        self.tasks
+            .range((after_current, Bound::Unbounded))
+            .chain(self.tasks.range(..))
+            .find(|&(id, task)| {
+                *id != IDLE_TASK_ID && task.get_state() == TaskState::Ready && filter(task)
+            })
+            .map(|(id, _)| *id)
+    }
+
+    // Also times out tasks blocked with a deadline, they find out they timed out when they run
+    fn wake_sleeping_tasks(&mut self, now: u32) {
+        for task in self.tasks.values_mut() {
+            if let TaskState::Sleeping(deadline) | TaskState::BlockedUntil(deadline) =
+                task.get_state()
+            {
+                if deadline_reached(now, deadline) {
+                    task.set_state(TaskState::Ready);
+                }
+            }
+        }
+    }
+
+    // Marks a task as exited and returns anything joining it, joiners may be on either core so the caller has to
+    // wake them with `wake_tasks`. They then find the result or learn the task is gone.
+    fn finish_task(&mut self, task_id: usize) -> Vec<usize> {
+        match self.tasks.get_mut(&task_id) {
+            Some(task) => {
+                task.set_state(TaskState::Exited);
+                // Only frees anything if the task is killed before it ever ran
+                task.free_args();
+                task.take_joiners()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    // Makes a blocked task ready again, any other state is left alone
+    fn wake(&mut self, task_id: usize) {
+        if let Some(task) = self.tasks.get_mut(&task_id) {
+            if let TaskState::Blocked | TaskState::BlockedUntil(_) = task.get_state() {
+                task.set_state(TaskState::Ready);
+            }
+        }
+    }
+
+    // Frees the stacks of exited tasks, the current task is skipped since we are still running on its stack
+    fn reap_exited_tasks(&mut self) {
+        let current = self.current_task_id;
+        self.tasks
+            .retain(|id, task| task.get_state() != TaskState::Exited || Some(*id) == current);
+    }
+
+    fn get_current_task_mut(&mut self) -> Option<&mut Task> {
+        self.tasks.get_mut(&self.current_task_id?)
+    }
+
+    // True if `task_id` is the task calling us, the other core's current task is running over there instead
+    fn is_caller(&self, task_id: usize) -> bool {
+        self.core == core_id() && self.current_task_id == Some(task_id)
+    }
+
+    // Looks up a task that other tasks are allowed to act on, the idle task and exited tasks are excluded
+    fn get_live_task_mut(&mut self, task_id: usize) -> Option<&mut Task> {
+        self.tasks
+            .get_mut(&task_id)
+            .filter(|task| task_id != IDLE_TASK_ID && task.get_state() != TaskState::Exited)
+    }
+
+    // If `current_task_id` is None (only on the first switch) we just ignore the store
     fn set_current_task_stack_pointer(&mut self, sp: usize) {
-        if let Some(idx) = self.current_task_idx {
-            unsafe {
-                self.tasks
-                    .get_unchecked_mut(idx)
-                    .as_mut()
-                    .unwrap()
-                    .set_task_sp(sp);
+        if let Some(task) = self.get_current_task_mut() {
+            task.set_task_sp(sp);
+        }
+    }
+
+    // The heap below an overflowed stack is already corrupt, so all we can do is stop and name the culprit
+    fn check_current_task_stack(&self) {
+        if let Some(id) = self.current_task_id {
+            let task = &self.tasks[&id];
+            if task.stack_overflowed() {
+                crate::panic!("Stack overflow in task {} ({})", id, task.get_name());
             }
         }
     }
 
-    // This function will panic if you call it without a current_task_idx being Some()
+    // This function will panic if you call it without a current_task_id being Some()
     fn get_current_task_stack_pointer(&mut self) -> usize {
-        self.tasks[self.current_task_idx.unwrap()]
-            .as_ref()
-            .unwrap()
-            .get_task_sp()
+        self.tasks[&self.current_task_id.unwrap()].get_task_sp()
     }
 
     pub fn add_task(&mut self, task: Task) -> Result<usize, SchedulerError> {
-        if let Some((idx, task_entry)) = self
-            .tasks
-            .iter_mut()
-            .enumerate()
-            .skip_while(|(_, val)| (**val).is_some())
-            .take(1)
-            .next()
-        {
-            PostOffice::register_mailbox(idx, task.get_name());
-            *task_entry = Some(task);
-            self.populated_tasks += 1;
-            Ok(idx)
-        } else {
-            Err(SchedulerError::TaskListFull)
+        let id = sync::cross_core_free(SCHEDULER_SPINLOCK, || self.reserve_task_id())?;
+        PostOffice::register_mailbox(
+            id,
+            task.get_name(),
+            task.get_mailbox_capacity(),
+            task.get_send_policy(),
+        )
+        .map_err(SchedulerError::PostOffice)?;
+        self.tasks.insert(id, task);
+        Ok(id)
+    }
+
+    // The scheduler spinlock must be held since task IDs are shared between cores
+    fn reserve_task_id(&mut self) -> Result<usize, SchedulerError> {
+        if self.get_task_count() >= MAX_TASKS {
+            return Err(SchedulerError::TaskListFull);
         }
+
+        let next_task_id = NEXT_TASK_ID.borrow();
+        let id = next_task_id.get();
+        next_task_id.set(id + 1);
+        Ok(id)
     }
 
+    // The idle task doesn't count towards the user task limit
     pub fn get_task_count(&self) -> usize {
-        self.populated_tasks
+        self.tasks.len() - 1
     }
 
-    pub fn start(self, mut systick: SYST) -> Result<(), SchedulerError> {
-        systick.set_clock_source(SystClkSource::Core);
-        match self.schedule_type {
-            ScheduleType::RoundRobin(interval) => systick.set_reload(interval),
+    pub fn stats(&self) -> SchedulerStats {
+        SchedulerStats {
+            core: self.core,
+            run_time_us: self.run_time_us,
+            tasks: self
+                .tasks
+                .iter()
+                .map(|(id, task)| task.get_stats(*id))
+                .collect(),
+        }
+    }
+
+    /// `system_clock` must be the frequency the core is running at once clocks are configured, such as
+    /// `clocks.system_clock.freq()`, since SysTick counts core cycles. The scheduler runs on the calling core.
+    pub fn start(
+        mut self,
+        mut systick: SYST,
+        scb: &mut SCB,
+        system_clock: Hertz<u32>,
+    ) -> Result<(), SchedulerError> {
+        let core = core_id();
+        let reload = self.check_startable(core, system_clock)?;
+        self.core = core;
+
+        // PendSV must be the lowest priority exception so a switch never happens on top of another handler
+        unsafe {
+            scb.set_priority(SystemHandler::PendSV, 0xFF);
         }
+
+        systick.set_clock_source(SystClkSource::Core);
+        systick.set_reload(reload);
         systick.clear_current();
 
-        if self.populated_tasks == 0 {
-            return Err(SchedulerError::NoPopulatedTasks);
+        // Every periodic task's first job is released as scheduling starts
+        let now = now_us();
+        for task in self.tasks.values_mut() {
+            task.release(now);
         }
 
+        let tickless = self.tickless;
+        self.systick = Some(systick);
+        with_schedulers(|scheds| scheds[core].replace(self));
+
+        // The handlers look the scheduler up, so their interrupts are only enabled once it is stored
+        with_scheduler(|sched| {
+            let systick = sched.systick.as_mut().unwrap();
+            systick.enable_counter();
+            systick.enable_interrupt();
+
+            // Both cores set their alarm's bit in the one enable register, holding the scheduler spinlock keeps the
+            // read-modify-write from racing the other core starting up
+            if tickless {
+                unsafe {
+                    (*pac::TIMER::ptr())
+                        .inte
+                        .modify(|r, w| w.bits(r.bits() | 1 << core));
+                }
+            }
+        });
+
         unsafe {
-            cortex_m::interrupt::disable();
-            SCHEDULER.borrow().borrow_mut().replace(self);
-            cortex_m::interrupt::enable();
+            if tickless {
+                NVIC::unmask(match core {
+                    0 => Interrupt::TIMER_IRQ_0,
+                    _ => Interrupt::TIMER_IRQ_1,
+                });
+            }
+
+            // The other core rings this when it wakes, spawns or suspends one of our tasks
+            NVIC::unmask(match core {
+                0 => Interrupt::SIO_IRQ_PROC0,
+                _ => Interrupt::SIO_IRQ_PROC1,
+            });
+        }
+
+        // Switch into the first task right away rather than waiting on the first tick
+        SCB::set_pendsv();
+
+        Ok(())
+    }
+
+    /// Boots core 1 through the SIO FIFO and starts this scheduler on it. This has to happen before `start` on
+    /// core 0, once core 0 is scheduling it uses the FIFO to signal core 1 and would swallow the boot handshake.
+    pub fn launch_core1(
+        self,
+        psm: &mut pac::PSM,
+        fifo: &mut SioFifo,
+        system_clock: Hertz<u32>,
+    ) -> Result<(), SchedulerError> {
+        self.check_startable(1, system_clock)?;
+        sync::cross_core_free(SCHEDULER_SPINLOCK, || {
+            CORE1_LAUNCH
+                .borrow()
+                .borrow_mut()
+                .replace((self, system_clock))
+        });
+
+        // Resetting core 1 puts it back in the bootrom waiting on the FIFO to be told where to start
+        psm.frce_off.modify(|_, w| w.proc1().set_bit());
+        while !psm.frce_off.read().proc1().bit_is_set() {}
+        psm.frce_off.modify(|_, w| w.proc1().clear_bit());
+
+        let vector_table = unsafe { (*SCB::PTR).vtor.read() };
+        let stack_pointer = unsafe { CORE1_STACK.0.as_ptr().add(CORE1_STACK_SIZE).addr() };
+        let boot_sequence = [
+            0,
+            0,
+            1,
+            vector_table,
+            stack_pointer as u32,
+            (core1_entry as *const u8).addr() as u32,
+        ];
+
+        // The bootrom echoes back every word it accepts and starts over on anything unexpected
+        let mut step = 0;
+        while step < boot_sequence.len() {
+            let word = boot_sequence[step];
+            if word == 0 {
+                // Stale words would throw off the echo, and the bootrom might be asleep in a `wfe`
+                fifo.drain();
+                cortex_m::asm::sev();
+            }
+
+            fifo.write_blocking(word);
+            step = if fifo.read_blocking() == word {
+                step + 1
+            } else {
+                0
+            };
+        }
+
+        debug!("Core 1 launched");
+        Ok(())
+    }
+
+    // Everything that could stop the scheduler running on `core`, returns the SysTick reload for its time slice
+    fn check_startable(
+        &self,
+        core: usize,
+        system_clock: Hertz<u32>,
+    ) -> Result<u32, SchedulerError> {
+        let time_slice = match self.schedule_type {
+            ScheduleType::RoundRobin(interval)
+            | ScheduleType::Priority(interval)
+            | ScheduleType::EarliestDeadlineFirst(interval)
+            | ScheduleType::RateMonotonic(interval) => interval,
+        };
+        let reload = time_slice_to_reload(time_slice, system_clock)?;
+
+        if self.get_task_count() == 0 {
+            return Err(SchedulerError::NoPopulatedTasks);
+        }
+
+        if self
+            .tasks
+            .values()
+            .any(|task| task.get_core().map_or(false, |pinned| pinned != core))
+        {
+            return Err(SchedulerError::WrongCore);
+        }
+
+        Ok(reload)
+    }
+}
+
+// SysTick fires when it counts down past zero, so the reload is one less than the cycles in a slice
+fn time_slice_to_reload(
+    time_slice: Microseconds<u32>,
+    system_clock: Hertz<u32>,
+) -> Result<u32, SchedulerError> {
+    let cycles = time_slice.integer() as u64 * system_clock.integer() as u64 / 1_000_000;
+
+    match cycles.checked_sub(1) {
+        Some(reload) if reload > 0 && reload <= 0x00FF_FFFF => Ok(reload as u32),
+        _ => Err(SchedulerError::InvalidTimeSlice),
+    }
+}
+
+// Both cores' schedulers, a core that hasn't started scheduling is None
+fn with_schedulers<R>(f: impl FnOnce(&mut [Option<Scheduler>; NUM_CORES]) -> R) -> R {
+    sync::cross_core_free(SCHEDULER_SPINLOCK, || {
+        f(&mut SCHEDULERS.borrow().borrow_mut())
+    })
+}
+
+// The calling core's scheduler
+fn with_scheduler<R>(f: impl FnOnce(&mut Scheduler) -> R) -> R {
+    with_schedulers(|scheds| f(scheds[core_id()].as_mut().unwrap()))
+}
+
+// Runs `f` on the scheduler of whichever core `task_id` lives on, then rings that core in case `f` changed
+// what it should be running
+fn with_task_scheduler<R>(
+    task_id: usize,
+    f: impl FnOnce(&mut Scheduler) -> Result<R, SchedulerError>,
+) -> Result<R, SchedulerError> {
+    with_schedulers(|scheds| {
+        let core = find_task_core(scheds, task_id).ok_or(SchedulerError::TaskNotFound)?;
+        let result = f(scheds[core].as_mut().unwrap());
+        notify_core(core);
+        result
+    })
+}
+
+fn find_task_core(scheds: &[Option<Scheduler>; NUM_CORES], task_id: usize) -> Option<usize> {
+    if task_id == IDLE_TASK_ID {
+        return None;
+    }
+
+    scheds.iter().position(|sched| {
+        sched
+            .as_ref()
+            .map_or(false, |sched| sched.tasks.contains_key(&task_id))
+    })
+}
+
+// Makes blocked tasks ready again on whichever core each one lives on, switching to one straight away if it should
+// preempt what this core is running. This is safe to call from interrupts.
+pub(crate) fn wake_tasks(task_ids: &[usize]) {
+    let preempt = with_schedulers(|scheds| {
+        task_ids.iter().fold(false, |preempt, task_id| {
+            wake_task_in(scheds, *task_id) || preempt
+        })
+    });
+
+    if preempt {
+        SCB::set_pendsv();
+    }
+}
+
+// `wake_tasks` for when the scheduler spinlock is already held, returns true if the woken task should preempt
+// what this core is running
+fn wake_task_in(scheds: &mut [Option<Scheduler>; NUM_CORES], task_id: usize) -> bool {
+    let core = match find_task_core(scheds, task_id) {
+        Some(core) => core,
+        None => return false,
+    };
+
+    let sched = scheds[core].as_mut().unwrap();
+    sched.wake(task_id);
+    notify_core(core);
+
+    let priority = sched.tasks[&task_id].get_priority();
+    core == core_id()
+        && priority_scheduling(sched)
+        && sched
+            .get_current_task_mut()
+            .map_or(false, |current| priority > current.get_priority())
+}
+
+/// ID of the task calling this, None from an interrupt handler or before the scheduler starts
+pub fn current_task_id() -> Option<usize> {
+    with_schedulers(|scheds| calling_task(scheds))
+}
+
+// Blocks the calling task (until `deadline` if there is one) and then drops `guard`, with interrupts masked so we can't
+// be switched out while still holding it. Whatever the task waits on has to be checked and changed under that guard,
+// then a `wake_task` made after the change can't be missed. It should come after the guard is dropped too, so a woken
+// task on the other core doesn't spin on the guard we still hold. Interrupt handlers hand things over without the
+// guard, so those are checked by `pending` with the scheduler spinlock held instead, and the task isn't blocked if it
+// says something arrived. The task stops running at its next yield.
+pub(crate) fn block_and_release<G>(
+    deadline: Option<u32>,
+    guard: G,
+    pending: impl FnOnce() -> bool,
+) {
+    cortex_m::interrupt::free(|_| {
+        with_schedulers(|scheds| {
+            if calling_task(scheds).is_some() && !pending() {
+                scheds[core_id()]
+                    .as_mut()
+                    .unwrap()
+                    .get_current_task_mut()
+                    .unwrap()
+                    .set_state(match deadline {
+                        Some(deadline) => TaskState::BlockedUntil(deadline),
+                        None => TaskState::Blocked,
+                    });
+            }
+        });
+        drop(guard);
+    });
+}
+
+// Wakes a task blocked on something outside the scheduler, this is safe to call from interrupts
+pub(crate) fn wake_task(task_id: usize) {
+    if with_schedulers(|scheds| wake_task_in(scheds, task_id)) {
+        SCB::set_pendsv();
+    }
+}
+
+/// The core the caller is running on, 0 or 1
+pub fn core_id() -> usize {
+    unsafe { (*pac::SIO::ptr()).cpuid.read().bits() as usize }
+}
+
+/// Takes a snapshot of the calling core's scheduler statistics, print it with defmt or format it for the UART task
+pub fn stats() -> SchedulerStats {
+    with_scheduler(|sched| sched.stats())
+}
+
+/// Reads the low 32 bits of the microsecond timer, this skips the `TIMER` lock so it is safe to use
+/// from interrupts and while another task holds the lock. It wraps roughly every 71 minutes.
+pub fn now_us() -> u32 {
+    unsafe { (*pac::TIMER::ptr()).timerawl.read().bits() }
+}
+
+// Compares with wrapping so deadlines keep working when the 32 bit timer rolls over
+pub(crate) fn deadline_reached(now: u32, deadline: u32) -> bool {
+    now.wrapping_sub(deadline) as i32 >= 0
+}
+
+/// Gives up the rest of the calling task's time slice by triggering a context switch right away
+pub fn yield_now() {
+    SCB::set_pendsv();
+}
+
+/// Parks the calling task until the timer (see `now_us`) reaches `deadline`
+pub fn sleep_until(deadline: u32) {
+    while !deadline_reached(now_us(), deadline) {
+        with_scheduler(|sched| {
+            if let Some(task) = sched.get_current_task_mut() {
+                task.set_state(TaskState::Sleeping(deadline));
+            }
+        });
+
+        // We aren't picked again until a SysTick sees the deadline has passed
+        yield_now();
+    }
+}
+
+/// Parks the calling task for at least `us` microseconds
+pub fn sleep_us(us: u32) {
+    sleep_until(now_us().wrapping_add(us));
+}
+
+/// Ends the calling periodic task's current job and sleeps until its next release, a late job is counted as a
+/// missed deadline. How late the task wakes after the release is recorded as its jitter. Does nothing for tasks
+/// without a period.
+pub fn wait_next_period() {
+    let next_release = with_scheduler(|sched| {
+        sched
+            .get_current_task_mut()
+            .and_then(|task| task.complete_job(now_us()))
+    });
+
+    if let Some(release) = next_release {
+        sleep_until(release);
+
+        with_scheduler(|sched| {
+            if let Some(task) = sched.get_current_task_mut() {
+                task.record_release_jitter(now_us());
+            }
+        });
+    }
+}
+
+/// Adds a task to a running scheduler, this is how tasks start other tasks once `Scheduler::start` has been called.
+/// A task pinned with `Task::with_core` goes to that core's scheduler, anything else runs on the calling core.
+pub fn spawn(mut task: Task) -> Result<usize, SchedulerError> {
+    let core = task.get_core().unwrap_or_else(core_id);
+    let id = with_schedulers(|scheds| {
+        scheds[core]
+            .as_mut()
+            .ok_or(SchedulerError::CoreNotRunning)?
+            .reserve_task_id()
+    })?;
+
+    // The mailbox has to exist before the task can first run, and the post office can't be touched in a critical section
+    PostOffice::register_mailbox(
+        id,
+        task.get_name(),
+        task.get_mailbox_capacity(),
+        task.get_send_policy(),
+    )
+    .map_err(SchedulerError::PostOffice)?;
+
+    let inserted = with_schedulers(|scheds| {
+        let sched = scheds[core].as_mut().unwrap();
+        // The core may have filled up with other spawns since the ID was reserved
+        if sched.get_task_count() >= MAX_TASKS {
+            return Err(SchedulerError::TaskListFull);
         }
 
-        systick.enable_counter();
-        systick.enable_interrupt();
+        task.release(now_us());
+        let priority = task.get_priority();
+        let period = task.get_period();
+        sched.tasks.insert(id, task);
+
+        let preempts = match sched.schedule_type {
+            ScheduleType::Priority(_) => sched
+                .get_current_task_mut()
+                .map_or(false, |current| priority > current.get_priority()),
+            ScheduleType::RateMonotonic(_) => {
+                sched.get_current_task_mut().map_or(false, |current| {
+                    match (period, current.get_period()) {
+                        (Some(new), Some(running)) => new < running,
+                        (new, running) => new.is_some() && running.is_none(),
+                    }
+                })
+            }
+            _ => false,
+        };
+
+        notify_core(core);
+        Ok(preempts && core == core_id())
+    });
+    if inserted.is_err() {
+        let _ = PostOffice::unregister_mailbox(id);
+    }
+    let preempts = inserted?;
 
+    debug!("Spawned task {} on core {}", id, core);
+    if preempts {
+        yield_now();
+    }
+    Ok(id)
+}
+
+/// Handle to the running scheduler so `add_task!(Spawner, "Name", task_fn(..))` can be used from inside a task
+pub struct Spawner;
+
+impl Spawner {
+    pub fn add_task(&self, task: Task) -> Result<usize, SchedulerError> {
+        spawn(task)
+    }
+}
+
+/// Ends the calling task, storing `result` for a `join` and freeing the task's mailbox. The task's stack is
+/// reclaimed on a later context switch, once we are no longer running on it.
+pub fn exit(result: Box<dyn Any + Send>) -> ! {
+    let task_id = with_scheduler(|sched| sched.current_task_id.unwrap());
+
+    // The post office is behind a spinlock so this has to happen from the task, not the scheduler
+    let _ = PostOffice::unregister_mailbox(task_id);
+
+    let joiners = with_scheduler(|sched| {
+        sched.results.insert(task_id, result);
+        sched.finish_task(task_id)
+    });
+    wake_tasks(&joiners);
+
+    debug!("Task {} exited", task_id);
+    loop {
+        yield_now();
+    }
+}
+
+/// Stops the task with `task_id` from being scheduled until it is resumed, this is safe to call from interrupts
+pub fn suspend(task_id: usize) -> Result<(), SchedulerError> {
+    let is_current = with_task_scheduler(task_id, |sched| {
+        sched
+            .get_live_task_mut(task_id)
+            .ok_or(SchedulerError::TaskNotFound)?
+            .set_state(TaskState::Suspended);
+        Ok(sched.is_caller(task_id))
+    })?;
+
+    if is_current {
+        yield_now();
+    }
+    Ok(())
+}
+
+/// Makes a suspended task schedulable again, a task that was sleeping or blocked re-checks what it was waiting on
+pub fn resume(task_id: usize) -> Result<(), SchedulerError> {
+    with_task_scheduler(task_id, |sched| {
+        let task = sched
+            .get_live_task_mut(task_id)
+            .ok_or(SchedulerError::TaskNotFound)?;
+        if task.get_state() == TaskState::Suspended {
+            task.set_state(TaskState::Ready);
+        }
         Ok(())
+    })
+}
+
+/// Ends the task with `task_id` wherever it currently is. Its stack and mailbox are freed, as are its arguments if it
+/// never ran. Once it has run its arguments belong to it, so they are leaked along with anything the task allocated
+/// itself, and any lock it holds is never released. Prefer `suspend` for tasks that share state. This is safe to call
+/// from interrupts, the post office is only taken behind a `Spinlock`.
+pub fn kill(task_id: usize) -> Result<(), SchedulerError> {
+    let exists = with_task_scheduler(task_id, |sched| {
+        Ok(sched.get_live_task_mut(task_id).is_some())
+    })?;
+    if !exists {
+        return Err(SchedulerError::TaskNotFound);
+    }
+
+    let _ = PostOffice::unregister_mailbox(task_id);
+
+    let (joiners, is_current) = with_task_scheduler(task_id, |sched| {
+        Ok((sched.finish_task(task_id), sched.is_caller(task_id)))
+    })?;
+    wake_tasks(&joiners);
+
+    debug!("Task {} killed", task_id);
+    if is_current {
+        // An interrupt handler that killed the task it interrupted returns first, the switch happens after it
+        if SCB::vect_active() != VectActive::ThreadMode {
+            yield_now();
+            return Ok(());
+        }
+        loop {
+            yield_now();
+        }
+    }
+    Ok(())
+}
+
+/// `suspend` for the task named `task_name`, this is safe to call from interrupts
+pub fn suspend_by_name(task_name: &str) -> Result<(), SchedulerError> {
+    suspend(task_id_by_name(task_name)?)
+}
+
+/// `resume` for the task named `task_name`, this is safe to call from interrupts
+pub fn resume_by_name(task_name: &str) -> Result<(), SchedulerError> {
+    resume(task_id_by_name(task_name)?)
+}
+
+/// `kill` for the task named `task_name`, this is safe to call from interrupts
+pub fn kill_by_name(task_name: &str) -> Result<(), SchedulerError> {
+    kill(task_id_by_name(task_name)?)
+}
+
+// Names are looked up in the task tables rather than the post office, they are unique since a task's mailbox is
+// registered under its name
+fn task_id_by_name(task_name: &str) -> Result<usize, SchedulerError> {
+    with_schedulers(|scheds| {
+        scheds.iter_mut().flatten().find_map(|sched| {
+            let task_id = sched
+                .tasks
+                .iter()
+                .find(|(_, task)| task.get_name() == task_name)
+                .map(|(id, _)| *id)?;
+            sched.get_live_task_mut(task_id).map(|_| task_id)
+        })
+    })
+    .ok_or(SchedulerError::TaskNotFound)
+}
+
+/// Blocks the calling task until the task with `task_id` exits and returns its result, the task can be on either
+/// core. A task's result can only be collected once and results that are never joined stay allocated. A killed
+/// task leaves no result, joining it returns `TaskNotFound`. Asking for the wrong type returns `JoinTypeMismatch` and
+/// leaves the result in place. Only a task can join, anywhere else this returns `NotCalledFromTask`.
+pub fn join<R: 'static>(task_id: usize) -> Result<R, SchedulerError> {
+    loop {
+        let result = with_schedulers(|scheds| {
+            if let Some(sched) = scheds
+                .iter_mut()
+                .flatten()
+                .find(|sched| sched.results.contains_key(&task_id))
+            {
+                if !sched.results[&task_id].is::<R>() {
+                    return Some(Err(SchedulerError::JoinTypeMismatch));
+                }
+                return Some(Ok(sched.results.remove(&task_id).unwrap()));
+            }
+
+            let caller = match calling_task(scheds) {
+                Some(caller) => caller,
+                None => return Some(Err(SchedulerError::NotCalledFromTask)),
+            };
+            match find_task_core(scheds, task_id) {
+                Some(task_core) => {
+                    let task = scheds[task_core]
+                        .as_mut()
+                        .unwrap()
+                        .tasks
+                        .get_mut(&task_id)
+                        .unwrap();
+                    // Its result was checked for above, so it was killed (or already joined) and is only waiting to
+                    // be reaped. Nothing would ever wake us.
+                    if task.get_state() == TaskState::Exited {
+                        return Some(Err(SchedulerError::TaskNotFound));
+                    }
+                    task.add_joiner(caller)
+                }
+                None => return Some(Err(SchedulerError::TaskNotFound)),
+            }
+            scheds[core_id()]
+                .as_mut()
+                .unwrap()
+                .get_current_task_mut()
+                .unwrap()
+                .set_state(TaskState::Blocked);
+            None
+        });
+
+        match result {
+            Some(result) => {
+                return result.and_then(|result| {
+                    result
+                        .downcast::<R>()
+                        .map(|result| *result)
+                        .map_err(|_| SchedulerError::JoinTypeMismatch)
+                })
+            }
+            None => yield_now(),
+        }
+    }
+}
+
+// What happened when a task tried to take a `sync::Mutex`
+enum MutexAttempt {
+    Acquired,
+    // The caller is queued on the mutex and blocked until it is handed over
+    Blocked,
+    // The caller isn't a task so it can't block, it has to keep trying
+    Busy,
+}
+
+// The task calling us, if we are being called from a task rather than an interrupt handler or `main`
+fn calling_task(scheds: &[Option<Scheduler>; NUM_CORES]) -> Option<usize> {
+    if SCB::vect_active() != VectActive::ThreadMode {
+        return None;
+    }
+
+    scheds[core_id()].as_ref()?.current_task_id
+}
+
+fn priority_scheduling(sched: &Scheduler) -> bool {
+    matches!(sched.schedule_type, ScheduleType::Priority(_))
+}
+
+// Looks up a task on either core
+fn find_task_mut(
+    scheds: &mut [Option<Scheduler>; NUM_CORES],
+    task_id: usize,
+) -> Option<(usize, &mut Scheduler)> {
+    let core = find_task_core(scheds, task_id)?;
+    Some((core, scheds[core].as_mut().unwrap()))
+}
+
+// Whether the task still exists and hasn't exited, a killed task lingers in the task table until it is reaped
+fn task_is_live(scheds: &mut [Option<Scheduler>; NUM_CORES], task_id: usize) -> bool {
+    find_task_mut(scheds, task_id).map_or(false, |(_, sched)| {
+        sched.get_live_task_mut(task_id).is_some()
+    })
+}
+
+pub(crate) fn lock_mutex(mutex: &NakedMutex<RefCell<MutexState>>) {
+    // Set once we are in the waiter list, a waiter finds itself the owner when the lock was handed to it
+    let mut waiting = false;
+
+    loop {
+        let attempt = with_schedulers(|scheds| {
+            let mut state = mutex.borrow().borrow_mut();
+            let caller = calling_task(scheds);
+
+            if !state.locked {
+                state.locked = true;
+                state.owner = caller;
+                return MutexAttempt::Acquired;
+            }
+
+            let caller = match caller {
+                Some(caller) => caller,
+                None => return MutexAttempt::Busy,
+            };
+            if waiting && state.owner == Some(caller) {
+                return MutexAttempt::Acquired;
+            }
+
+            if !state.waiters.contains(&caller) {
+                state.waiters.push(caller);
+            }
+
+            let sched = scheds[core_id()].as_mut().unwrap();
+            let task = sched.get_current_task_mut().unwrap();
+            task.set_state(TaskState::Blocked);
+            let priority = task.get_priority();
+
+            // The holder runs at our priority until it unlocks, so a task in between can't hold us both up
+            if let Some((core, owner_sched)) =
+                state.owner.and_then(|owner| find_task_mut(scheds, owner))
+            {
+                if priority_scheduling(owner_sched) {
+                    owner_sched
+                        .tasks
+                        .get_mut(&state.owner.unwrap())
+                        .unwrap()
+                        .inherit_priority(mutex_key(mutex), priority);
+                    notify_core(core);
+                }
+            }
+
+            MutexAttempt::Blocked
+        });
+
+        match attempt {
+            MutexAttempt::Acquired => return,
+            MutexAttempt::Blocked => {
+                waiting = true;
+                yield_now();
+            }
+            MutexAttempt::Busy => {}
+        }
+    }
+}
+
+// Identifies a mutex in a task's inherited priorities
+fn mutex_key(mutex: &NakedMutex<RefCell<MutexState>>) -> usize {
+    (mutex as *const NakedMutex<RefCell<MutexState>>).addr()
+}
+
+pub(crate) fn try_lock_mutex(mutex: &NakedMutex<RefCell<MutexState>>) -> bool {
+    with_schedulers(|scheds| {
+        let mut state = mutex.borrow().borrow_mut();
+        if state.locked {
+            return false;
+        }
+
+        state.locked = true;
+        state.owner = calling_task(scheds);
+        true
+    })
+}
+
+pub(crate) fn unlock_mutex(mutex: &NakedMutex<RefCell<MutexState>>) {
+    let preempted = with_schedulers(|scheds| {
+        let mut state = mutex.borrow().borrow_mut();
+
+        // Only the boost this mutex gave is dropped, waiters on other mutexes the holder still has keep theirs
+        let releasing_priority = match state.owner.and_then(|owner| find_task_mut(scheds, owner)) {
+            Some((_, sched)) => {
+                let task = sched.tasks.get_mut(&state.owner.unwrap()).unwrap();
+                task.release_inherited_priority(mutex_key(mutex));
+                Some(task.get_priority())
+            }
+            None => None,
+        };
+
+        // Waiters that were killed while queued can't be handed anything
+        state.waiters.retain(|waiter| task_is_live(scheds, *waiter));
+
+        let priority_of = |waiter: &usize| {
+            let core = find_task_core(scheds, *waiter).unwrap();
+            scheds[core].as_ref().unwrap().tasks[waiter].get_priority()
+        };
+        let priority_order = scheds[core_id()]
+            .as_ref()
+            .map_or(false, priority_scheduling);
+        let next = if priority_order {
+            // Earliest waiter wins a tie, so waiters of equal priority are served in order
+            state
+                .waiters
+                .iter()
+                .enumerate()
+                .max_by(|(a_index, a), (b_index, b)| {
+                    priority_of(*a)
+                        .cmp(&priority_of(*b))
+                        .then(b_index.cmp(a_index))
+                })
+                .map(|(index, _)| index)
+        } else if state.waiters.is_empty() {
+            None
+        } else {
+            Some(0)
+        };
+
+        let next = match next {
+            Some(index) => state.waiters.remove(index),
+            None => {
+                state.locked = false;
+                state.owner = None;
+                return false;
+            }
+        };
+
+        // The lock goes straight to the next waiter so nothing can take it in between
+        state.owner = Some(next);
+        let boost = state.waiters.iter().map(priority_of).max();
+        let (core, sched) = find_task_mut(scheds, next).unwrap();
+        let by_priority = priority_scheduling(sched);
+        let task = sched.tasks.get_mut(&next).unwrap();
+        if by_priority {
+            if let Some(boost) = boost {
+                task.inherit_priority(mutex_key(mutex), boost);
+            }
+        }
+        let next_priority = task.get_priority();
+        sched.wake(next);
+        notify_core(core);
+
+        by_priority
+            && core == core_id()
+            && releasing_priority.map_or(false, |releasing| next_priority > releasing)
+    });
+
+    if preempted {
+        yield_now();
+    }
+}
+
+// What happened when a task checked a `sync` primitive it is waiting on
+enum WaitAttempt<R> {
+    // The wait is over, None if it timed out
+    Done(Option<R>),
+    // The caller is queued on the primitive and blocked until it is notified
+    Blocked,
+    // The caller isn't a task so it can't block, it has to keep checking
+    Busy,
+}
+
+// Waits on a `sync` primitive until `poll` returns Some or the timer reaches `deadline`. `poll` is handed the
+// calling task and runs with the scheduler spinlock held, so a notify can't slip in between it failing and the
+// caller blocking. Outside of a task this spins until `poll` succeeds or the deadline passes.
+pub(crate) fn wait_for<S, R>(
+    primitive: &NakedMutex<RefCell<S>>,
+    deadline: Option<u32>,
+    waiters: impl Fn(&mut S) -> &mut WaitList,
+    mut poll: impl FnMut(&mut S, Option<usize>) -> Option<R>,
+) -> Option<R> {
+    loop {
+        let attempt = with_schedulers(|scheds| {
+            let mut borrow = primitive.borrow().borrow_mut();
+            let state = &mut *borrow;
+            let caller = calling_task(scheds);
+
+            let result = poll(state, caller);
+            let timed_out = deadline.map_or(false, |deadline| deadline_reached(now_us(), deadline));
+            if result.is_some() || timed_out {
+                if let Some(caller) = caller {
+                    waiters(state).remove(caller);
+                }
+                return WaitAttempt::Done(result);
+            }
+
+            let caller = match caller {
+                Some(caller) => caller,
+                None => return WaitAttempt::Busy,
+            };
+            waiters(state).add(caller);
+            scheds[core_id()]
+                .as_mut()
+                .unwrap()
+                .get_current_task_mut()
+                .unwrap()
+                .set_state(match deadline {
+                    Some(deadline) => TaskState::BlockedUntil(deadline),
+                    None => TaskState::Blocked,
+                });
+
+            WaitAttempt::Blocked
+        });
+
+        match attempt {
+            WaitAttempt::Done(result) => return result,
+            WaitAttempt::Blocked => yield_now(),
+            WaitAttempt::Busy => {}
+        }
+    }
+}
+
+// Queues the calling task on a `sync` primitive ahead of `wait_for`, for primitives that have to be waited on
+// before the caller gives something else up
+pub(crate) fn enqueue<S>(
+    primitive: &NakedMutex<RefCell<S>>,
+    waiters: impl Fn(&mut S) -> &mut WaitList,
+) {
+    with_schedulers(|scheds| {
+        if let Some(caller) = calling_task(scheds) {
+            waiters(&mut primitive.borrow().borrow_mut()).add(caller);
+        }
+    });
+}
+
+// Applies `update` to a `sync` primitive and wakes the first task waiting on it, or all of them with `wake_all`.
+// This is safe to call from interrupts, a woken task that outranks the running one is switched to straight away.
+pub(crate) fn notify<S, R>(
+    primitive: &NakedMutex<RefCell<S>>,
+    waiters: impl Fn(&mut S) -> &mut WaitList,
+    wake_all: bool,
+    update: impl FnOnce(&mut S) -> R,
+) -> R {
+    let (result, preempt) = with_schedulers(|scheds| {
+        let mut borrow = primitive.borrow().borrow_mut();
+        let state = &mut *borrow;
+        let result = update(state);
+
+        let mut preempt = false;
+        let waiters = waiters(state);
+        while let Some(task_id) = waiters.take_first() {
+            // A waiter killed while queued can't take the wake, it goes to the next one instead
+            if !task_is_live(scheds, task_id) {
+                continue;
+            }
+            preempt |= wake_task_in(scheds, task_id);
+            if !wake_all {
+                break;
+            }
+        }
+
+        (result, preempt)
+    });
+
+    if preempt {
+        SCB::set_pendsv();
     }
+    result
 }
@@ -1,49 +1,161 @@
 use core::marker::PhantomData;
 
-use crate::constants::TASK_STACK_SIZE;
+use crate::constants::{
+    MAILBOX_CAPACITY, NUM_CORES, STACK_CANARY, STACK_CANARY_WORDS, STACK_PAINT, TASK_STACK_SIZE,
+};
+use crate::services::post_office::SendPolicy;
+use crate::services::scheduler;
 use alloc::boxed::Box;
 use alloc::string::String;
+use alloc::vec::Vec;
 
-enum TaskState {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum TaskState {
+    /// Waiting to be picked by the scheduler
     Ready,
+    /// Currently executing on the CPU
+    Running,
+    /// Waiting on another task or event to make it ready again
+    Blocked,
+    /// `Blocked`, but gives up waiting once the timer reaches the contained deadline (in microseconds)
+    BlockedUntil(u32),
+    /// Parked until the timer reaches the contained deadline (in microseconds)
+    Sleeping(u32),
+    /// Removed from scheduling until it is explicitly resumed
+    Suspended,
+    /// Finished and will never be scheduled again
+    Exited,
 }
 
 // #[derive(Clone)]
 pub struct Task {
     name: String,
     stack_pointer: usize,
-    args: Box<dyn TaskArgument>,
     stack: alloc::vec::Vec<u32>,
-    _priority: u8,
-    _state: TaskState,
+    // Boxed arguments `task_entry` is handed in r0, they are ours to free until the task is first switched to
+    args: Option<TaskArgs>,
+    priority: u8,
+    // Raised while the task holds a `sync::Mutex` a higher priority task is waiting on, kept per mutex (keyed by its
+    // address) so unlocking one doesn't drop a boost another still held mutex gave
+    inherited_priorities: Vec<(usize, u8)>,
+    // Core the task must run on, unpinned tasks run on whichever core they were added to
+    core: Option<usize>,
+    // Shape of the mailbox registered for the task when it is added to a scheduler
+    mailbox_capacity: usize,
+    send_policy: SendPolicy,
+    state: TaskState,
+    // Tasks blocked in `scheduler::join` waiting for this one to exit
+    joiners: Vec<usize>,
+    times_scheduled: u32,
+    // Microseconds spent running, counted at each context switch
+    run_time_us: u64,
+    // Periodic timing in microseconds, only tasks with a period take part in deadline scheduling
+    period: Option<u32>,
+    relative_deadline: Option<u32>,
+    release_at: u32,
+    absolute_deadline: Option<u32>,
+    missed_deadlines: u32,
+    // Worst delay between a job's release and the task running again, and jobs still running at their next release
+    max_release_jitter: u32,
+    overruns: u32,
     // phantom: PhantomData<&'a u8>,
 }
 
 impl<'a> Task {
-    pub fn new<T>(name: &str, function_pointer: fn(Box<T>) -> !, args: T) -> Self
+    pub fn new<T, R>(name: &str, function_pointer: fn(Box<T>) -> R, args: T) -> Self
     where
         T: TaskArgument + 'static,
+        R: Send + 'static,
     {
-        let mut stack = alloc::vec![0; TASK_STACK_SIZE];
+        let stack_size = T::STACK_SIZE;
+        assert!(
+            stack_size > STACK_CANARY_WORDS + 16,
+            "Task stack is too small to hold its initial context"
+        );
+        let mut stack = alloc::vec![STACK_PAINT; stack_size];
 
-        let stack_pointer = unsafe { stack.as_ptr().add(TASK_STACK_SIZE - 16).addr() };
-        let boxed_args = Box::new(args);
+        // The bottom of the stack is filled with a known pattern, if it is ever overwritten the task overflowed
+        stack[..STACK_CANARY_WORDS].fill(STACK_CANARY);
 
-        stack[TASK_STACK_SIZE - 1] = 1 << 24;
-        stack[TASK_STACK_SIZE - 2] = (function_pointer as *const u8).addr() as u32;
-        stack[TASK_STACK_SIZE - 8] = (&*boxed_args as *const T).addr() as u32;
+        let stack_pointer = unsafe { stack.as_ptr().add(stack_size - 16).addr() };
+
+        // Ownership of the arguments moves to the task itself once it first runs, they are freed when the task
+        // function returns. Until then dropping the `Task` frees them.
+        let boxed_args = Box::into_raw(Box::new(args));
+
+        stack[stack_size - 1] = 1 << 24;
+        stack[stack_size - 2] = (task_entry::<T, R> as *const u8).addr() as u32;
+        stack[stack_size - 7] = (function_pointer as *const u8).addr() as u32;
+        stack[stack_size - 8] = boxed_args.addr() as u32;
 
         Self {
             name: name.into(),
             stack_pointer,
-            args: boxed_args,
             stack: stack,
-            _priority: 0,
-            _state: TaskState::Ready,
+            args: Some(TaskArgs {
+                pointer: boxed_args.cast(),
+                drop: drop_args::<T>,
+            }),
+            priority: 0,
+            inherited_priorities: Vec::new(),
+            core: None,
+            mailbox_capacity: MAILBOX_CAPACITY,
+            send_policy: SendPolicy::Block,
+            state: TaskState::Ready,
+            joiners: Vec::new(),
+            times_scheduled: 0,
+            run_time_us: 0,
+            period: T::PERIOD_US,
+            relative_deadline: None,
+            release_at: 0,
+            absolute_deadline: None,
+            missed_deadlines: 0,
+            max_release_jitter: 0,
+            overruns: 0,
             // phantom: PhantomData,
         }
     }
 
+    /// Sets the priority used by `ScheduleType::Priority`, higher values are scheduled first
+    pub fn with_priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Pins the task to `core` (0 or 1). `scheduler::spawn` hands a pinned task to that core's scheduler, and a
+    /// scheduler refuses to start with a task pinned to the other core.
+    pub fn with_core(mut self, core: usize) -> Self {
+        assert!(core < NUM_CORES, "The RP2040 only has cores 0 and 1");
+        self.core = Some(core);
+        self
+    }
+
+    /// Sets how many messages the task's mailbox holds before sends to it apply its `SendPolicy`
+    pub fn with_mailbox_capacity(mut self, capacity: usize) -> Self {
+        assert!(capacity > 0, "A mailbox has to hold at least one message");
+        self.mailbox_capacity = capacity;
+        self
+    }
+
+    /// Sets what sending to the task's mailbox does once it is full, `SendPolicy::Block` by default
+    pub fn with_send_policy(mut self, policy: SendPolicy) -> Self {
+        self.send_policy = policy;
+        self
+    }
+
+    /// Makes this a periodic task released every `period_us` microseconds, each job should end with a call to
+    /// `scheduler::wait_next_period`. The relative deadline defaults to the period.
+    pub fn with_period(mut self, period_us: u32) -> Self {
+        self.period = Some(period_us);
+        self
+    }
+
+    /// Sets how long after each release a periodic task's job must finish, used by `ScheduleType::EarliestDeadlineFirst`
+    pub fn with_deadline(mut self, deadline_us: u32) -> Self {
+        self.relative_deadline = Some(deadline_us);
+        self
+    }
+
     pub(crate) fn set_task_sp(&mut self, sp: usize) {
         self.stack_pointer = sp;
     }
@@ -52,9 +164,231 @@ impl<'a> Task {
         self.stack_pointer
     }
 
+    /// Checks the saved stack pointer is above the canary and the canary is intact
+    pub(crate) fn stack_overflowed(&self) -> bool {
+        let stack_limit = unsafe { self.stack.as_ptr().add(STACK_CANARY_WORDS).addr() };
+
+        self.stack_pointer < stack_limit
+            || self.stack[..STACK_CANARY_WORDS]
+                .iter()
+                .any(|word| *word != STACK_CANARY)
+    }
+
     pub fn get_name(&self) -> &str {
         &self.name
     }
+
+    /// The priority the task is scheduled at, including any it inherited from tasks waiting on a mutex it holds
+    pub fn get_priority(&self) -> u8 {
+        self.inherited_priorities
+            .iter()
+            .map(|(_, inherited)| *inherited)
+            .fold(self.priority, u8::max)
+    }
+
+    pub(crate) fn inherit_priority(&mut self, mutex: usize, priority: u8) {
+        match self
+            .inherited_priorities
+            .iter_mut()
+            .find(|(held, _)| *held == mutex)
+        {
+            Some((_, inherited)) => *inherited = (*inherited).max(priority),
+            None => self.inherited_priorities.push((mutex, priority)),
+        }
+    }
+
+    pub(crate) fn release_inherited_priority(&mut self, mutex: usize) {
+        self.inherited_priorities.retain(|(held, _)| *held != mutex);
+    }
+
+    pub fn get_core(&self) -> Option<usize> {
+        self.core
+    }
+
+    pub fn get_mailbox_capacity(&self) -> usize {
+        self.mailbox_capacity
+    }
+
+    pub fn get_send_policy(&self) -> SendPolicy {
+        self.send_policy
+    }
+
+    pub fn get_state(&self) -> TaskState {
+        self.state
+    }
+
+    pub(crate) fn set_state(&mut self, state: TaskState) {
+        self.state = state;
+    }
+
+    pub fn get_period(&self) -> Option<u32> {
+        self.period
+    }
+
+    pub fn get_absolute_deadline(&self) -> Option<u32> {
+        self.absolute_deadline
+    }
+
+    /// Releases the periodic task's next job at `release_at` and works out the job's absolute deadline
+    pub(crate) fn release(&mut self, release_at: u32) {
+        if let Some(period) = self.period {
+            self.release_at = release_at;
+            self.absolute_deadline =
+                Some(release_at.wrapping_add(self.relative_deadline.unwrap_or(period)));
+        }
+    }
+
+    /// Ends the current job at `now`, counting a missed deadline if it ran late and an overrun if it ran into the next
+    /// release, and returns when the next job is released. Tasks without a period have no next release.
+    pub(crate) fn complete_job(&mut self, now: u32) -> Option<u32> {
+        let period = self.period?;
+
+        if let Some(deadline) = self.absolute_deadline {
+            if now.wrapping_sub(deadline) as i32 > 0 {
+                self.missed_deadlines = self.missed_deadlines.wrapping_add(1);
+            }
+        }
+
+        let next_release = self.release_at.wrapping_add(period);
+        if now.wrapping_sub(next_release) as i32 > 0 {
+            self.overruns = self.overruns.wrapping_add(1);
+        }
+
+        self.release(next_release);
+        Some(next_release)
+    }
+
+    /// Records how long after the current job's release the task got to run at `now`
+    pub(crate) fn record_release_jitter(&mut self, now: u32) {
+        let jitter = now.wrapping_sub(self.release_at);
+        self.max_release_jitter = self.max_release_jitter.max(jitter);
+    }
+
+    pub(crate) fn record_scheduled(&mut self) {
+        self.times_scheduled = self.times_scheduled.wrapping_add(1);
+        // `task_entry` owns the arguments from the first time the task runs
+        self.args = None;
+    }
+
+    /// Frees the arguments of a task that never ran, a task that did run owns them itself
+    pub(crate) fn free_args(&mut self) {
+        if let Some(args) = self.args.take() {
+            unsafe { (args.drop)(args.pointer) };
+        }
+    }
+
+    pub(crate) fn record_run_time(&mut self, elapsed_us: u32) {
+        self.run_time_us += elapsed_us as u64;
+    }
+
+    /// Deepest the stack has been, found by counting the painted words the task never overwrote
+    pub fn get_stack_high_water(&self) -> usize {
+        let untouched = self.stack[STACK_CANARY_WORDS..]
+            .iter()
+            .take_while(|word| **word == STACK_PAINT)
+            .count();
+
+        self.stack.len() - STACK_CANARY_WORDS - untouched
+    }
+
+    pub fn get_stats(&self, task_id: usize) -> TaskStats {
+        TaskStats {
+            task_id,
+            name: self.name.clone(),
+            state: self.state,
+            priority: self.get_priority(),
+            stack_size: self.stack.len(),
+            stack_high_water: self.get_stack_high_water(),
+            times_scheduled: self.times_scheduled,
+            run_time_us: self.run_time_us,
+            missed_deadlines: self.missed_deadlines,
+            max_release_jitter_us: self.max_release_jitter,
+            overruns: self.overruns,
+        }
+    }
+
+    pub(crate) fn add_joiner(&mut self, task_id: usize) {
+        if !self.joiners.contains(&task_id) {
+            self.joiners.push(task_id);
+        }
+    }
+
+    pub(crate) fn take_joiners(&mut self) -> Vec<usize> {
+        core::mem::take(&mut self.joiners)
+    }
+}
+
+/// Snapshot of a task's bookkeeping, stack sizes are in words
+#[derive(Debug, Clone)]
+pub struct TaskStats {
+    pub task_id: usize,
+    pub name: String,
+    pub state: TaskState,
+    pub priority: u8,
+    pub stack_size: usize,
+    pub stack_high_water: usize,
+    pub times_scheduled: u32,
+    pub run_time_us: u64,
+    pub missed_deadlines: u32,
+    pub max_release_jitter_us: u32,
+    pub overruns: u32,
 }
 
-pub trait TaskArgument: Send {}
+impl defmt::Format for TaskStats {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "{} {=str} | {} | priority {} | stack {}/{} | scheduled {} | ran {}us | missed deadlines {} | max jitter {}us | overruns {}",
+            self.task_id,
+            self.name.as_str(),
+            self.state,
+            self.priority,
+            self.stack_high_water,
+            self.stack_size,
+            self.times_scheduled,
+            self.run_time_us,
+            self.missed_deadlines,
+            self.max_release_jitter_us,
+            self.overruns
+        )
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        self.free_args();
+    }
+}
+
+// Type erased so `Task` doesn't need the argument type
+struct TaskArgs {
+    pointer: *mut (),
+    drop: unsafe fn(*mut ()),
+}
+
+// The pointer is only ever used by whichever of `Task` or `task_entry` owns the arguments, and `TaskArgument` is Send
+unsafe impl Send for TaskArgs {}
+
+unsafe fn drop_args<T>(pointer: *mut ()) {
+    drop(Box::from_raw(pointer.cast::<T>()));
+}
+
+// Every task starts executing here with the boxed arguments in r0 and the task function in r1, once the task
+// function returns its result is handed to the scheduler so a `join` can pick it up
+extern "C" fn task_entry<T, R>(args: *mut T, function_pointer: usize) -> !
+where
+    T: TaskArgument + 'static,
+    R: Send + 'static,
+{
+    let function: fn(Box<T>) -> R = unsafe { core::mem::transmute(function_pointer) };
+    let result = function(unsafe { Box::from_raw(args) });
+
+    scheduler::exit(Box::new(result))
+}
+
+pub trait TaskArgument: Send {
+    /// Size of the task's stack in words, set with `#[task(stack_size = ...)]`
+    const STACK_SIZE: usize = TASK_STACK_SIZE;
+    /// Release period in microseconds of a periodic task, set with `#[task(period_us = ...)]`
+    const PERIOD_US: Option<u32> = None;
+}
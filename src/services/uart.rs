@@ -1,8 +1,11 @@
 use super::post_office::{MailboxMessageType, PostOffice};
+use super::scheduler;
 use crate::bsp::hal::uart::{self, Enabled, UartPeripheral};
-use crate::pac::UART0;
+use crate::constants::ISR_MESSAGE_SIZE;
+use crate::pac::{self, interrupt, Interrupt, UART0};
 use crate::task;
 use crate::Task;
+use crate::TaskArgument;
 use crate::{
     bsp::hal::gpio::{
         bank0::{Gpio0, Gpio1},
@@ -10,14 +13,16 @@ use crate::{
         pin::Uart,
         FunctionUart, Pin, Pins,
     },
-    sync::Spinlock,
+    sync::Mutex,
 };
-use crate::{Mutex, TaskArgument};
 use core::borrow::BorrowMut;
 use core::cell::RefCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use cortex_m::peripheral::NVIC;
 use defmt::*;
 
-static UART: Spinlock<
+// Writes block for as long as the bytes take to go out, so waiters sleep in the scheduler instead of spinning
+static UART: Mutex<
     RefCell<
         Option<
             UartPeripheral<
@@ -27,7 +32,28 @@ static UART: Spinlock<
             >,
         >,
     >,
-> = Spinlock::new(RefCell::new(None));
+> = Mutex::new(RefCell::new(None));
+
+// ID of the UART task, stored before its receive interrupt is unmasked so the handler never has to look it up
+static UART_TASK_ID: AtomicUsize = AtomicUsize::new(0);
+
+// Hands received bytes to the UART task's mailbox. Reading the receive FIFO until it is empty (or a message is full)
+// clears the interrupt, anything left over raises it again.
+#[interrupt]
+fn UART0_IRQ() {
+    let uart = unsafe { &*pac::UART0::ptr() };
+    let mut data = [0; ISR_MESSAGE_SIZE];
+    let mut len = 0;
+    while len < data.len() && uart.uartfr.read().rxfe().bit_is_clear() {
+        data[len] = uart.uartdr.read().data().bits();
+        len += 1;
+    }
+
+    let task_idx = UART_TASK_ID.load(Ordering::Relaxed);
+    if let Err(err) = PostOffice::post_from_isr(task_idx, &data[..len]) {
+        debug!("UART received {} bytes that were dropped: {}", len, err);
+    }
+}
 
 // pub struct UartArguments {
 //     pub uart: RefCell<
@@ -72,7 +98,8 @@ pub fn uart_task(
     )
 }
 
-#[task]
+// The blocking UART writes and defmt logging need more room than the default stack
+#[task(stack_size = 512)]
 pub fn uart(
     uart: RefCell<
         Option<
@@ -86,15 +113,29 @@ pub fn uart(
 ) -> ! {
     UART.lock().borrow_mut().replace(uart.borrow_mut().take());
 
+    UART_TASK_ID.store(scheduler::current_task_id().unwrap(), Ordering::Relaxed);
+
+    // Interrupts from the receive FIFO filling or going quiet, they are taken on the core this task runs on
+    unsafe {
+        (*pac::UART0::ptr())
+            .uartimsc
+            .modify(|_, w| w.rxim().set_bit().rtim().set_bit());
+        NVIC::unmask(Interrupt::UART0_IRQ);
+    }
+
     debug!("UART initialization complete!");
     loop {
-        if let Ok(Some(msg)) = PostOffice::recv_by_name("UART".into()) {
+        // Parks the task until something is sent to the UART mailbox
+        if let Ok(Some(msg)) = PostOffice::recv_blocking(None) {
             match msg.data {
                 MailboxMessageType::Uart(data) => {
                     let mut lock = UART.lock();
                     let writer = lock.get_mut().as_mut().unwrap();
                     writer.write_full_blocking(&data);
                 }
+                MailboxMessageType::Interrupt(data) => {
+                    debug!("UART received {}", data.as_slice());
+                }
                 _ => {
                     debug!("Unexpected message type in UART Mailbox");
                 }
@@ -9,6 +9,7 @@ use usb_device::prelude::*;
 use usbd_hid_device::{Hid, USB_CLASS_HID};
 
 use crate::bsp::hal::pac::{interrupt, Interrupt};
+use crate::sync::EventFlags;
 
 use super::report::*;
 
@@ -19,6 +20,11 @@ struct Usb {
 
 static USB: Mutex<RefCell<Option<Usb>>> = Mutex::new(RefCell::new(None));
 
+/// Updated from the USB interrupt, wait on `USB_CONFIGURED` before sending reports instead of polling
+pub static USB_EVENTS: EventFlags = EventFlags::new();
+/// Set while the host has the keyboard configured
+pub const USB_CONFIGURED: u32 = 1 << 0;
+
 #[interrupt]
 unsafe fn USBCTRL_IRQ() {
     usb_interrupt();
@@ -29,6 +35,12 @@ fn usb_interrupt() {
         let mut borrow = USB.borrow(&cs).borrow_mut();
         let usb = &mut borrow.as_mut().unwrap();
         usb.device.poll(&mut [&mut usb.hid]);
+
+        if usb.device.state() == UsbDeviceState::Configured {
+            USB_EVENTS.set(USB_CONFIGURED);
+        } else {
+            USB_EVENTS.clear(USB_CONFIGURED);
+        }
     })
 }
 
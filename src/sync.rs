@@ -1,8 +1,21 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use alloc::vec::Vec;
+use alloc_cortex_m::CortexMHeap;
 use core::{
-    cell::UnsafeCell,
-    sync::atomic::{AtomicBool, Ordering},
+    cell::{RefCell, UnsafeCell},
+    sync::atomic::{compiler_fence, AtomicBool, Ordering},
 };
-use lock_api::{GuardSend, Mutex, RawMutex};
+use lock_api::{GuardNoSend, RawMutex};
+
+use crate::pac;
+use crate::services::scheduler;
+
+// SIO hardware spinlocks the kernel keeps for itself. Locks are taken in this order (scheduler before heap) and
+// never nested otherwise, since a hardware spinlock can't be taken twice. The lock word one is only held to test
+// and set a `Spinlock` so nothing is ever taken inside it.
+pub(crate) const SCHEDULER_SPINLOCK: usize = 31;
+pub(crate) const HEAP_SPINLOCK: usize = 30;
+pub(crate) const LOCK_WORD_SPINLOCK: usize = 29;
 
 pub struct NakedMutex<T> {
     inner: UnsafeCell<T>,
@@ -26,30 +39,418 @@ impl<T> NakedMutex<T> {
 
 unsafe impl<T> Sync for NakedMutex<T> where T: Send {}
 
-pub struct RawSpinlock(AtomicBool);
+/// There are only 32 hardware spinlocks, too few to give one to every `Spinlock`, so each lock is a flag that is
+/// tested and set while holding `LOCK_WORD_SPINLOCK`. The hardware spinlock keeps the other core out.
+///
+/// Interrupts on this core stay masked for as long as the lock is held, so a holder is never preempted by a task
+/// or a handler that wants the same lock, which makes it safe to take from interrupt handlers. They are unmasked
+/// between attempts while spinning. Guards have to be dropped in the reverse order they were taken, on the core
+/// that took them, and should be held only briefly.
+pub struct RawSpinlock {
+    locked: AtomicBool,
+    // Whether interrupts were enabled before the lock was taken, only touched by the holder
+    restore_interrupts: AtomicBool,
+}
 
 unsafe impl RawMutex for RawSpinlock {
-    const INIT: Self = RawSpinlock(AtomicBool::new(false));
+    const INIT: Self = RawSpinlock {
+        locked: AtomicBool::new(false),
+        restore_interrupts: AtomicBool::new(false),
+    };
 
-    type GuardMarker = GuardSend;
+    // Unlocking restores the interrupt mask of the core that locked it
+    type GuardMarker = GuardNoSend;
 
     fn lock(&self) {
         while !self.try_lock() {}
     }
 
     fn try_lock(&self) -> bool {
-        if !self.0.load(Ordering::Acquire) {
-            self.0.store(true, Ordering::Release);
-            true
-        } else {
-            false
+        let interrupts_enabled = cortex_m::register::primask::read().is_active();
+        cortex_m::interrupt::disable();
+
+        let acquired = cross_core_free(LOCK_WORD_SPINLOCK, || {
+            if !self.locked.load(Ordering::Acquire) {
+                self.locked.store(true, Ordering::Release);
+                true
+            } else {
+                false
+            }
+        });
+
+        if acquired {
+            self.restore_interrupts
+                .store(interrupts_enabled, Ordering::Relaxed);
+        } else if interrupts_enabled {
+            unsafe { cortex_m::interrupt::enable() };
         }
+        acquired
     }
 
     unsafe fn unlock(&self) {
-        self.0.store(false, Ordering::Release)
+        let restore_interrupts = self.restore_interrupts.load(Ordering::Relaxed);
+        self.locked.store(false, Ordering::Release);
+        if restore_interrupts {
+            cortex_m::interrupt::enable();
+        }
     }
 }
 
 pub type Spinlock<T> = lock_api::Mutex<RawSpinlock, T>;
 pub type SpinlockGuard<'a, T> = lock_api::MutexGuard<'a, RawSpinlock, T>;
+
+/// Lock behind `Mutex`. A task that finds it taken is blocked in the scheduler rather than spinning, and on unlock
+/// the lock is handed straight to the next waiter. Under `ScheduleType::Priority` the waiter with the highest
+/// priority goes first and the holder inherits that priority until it unlocks.
+///
+/// Outside of a task (in `main` before the scheduler starts) it spins instead. It must not be taken from an
+/// interrupt handler.
+pub struct RawBlockingMutex {
+    state: NakedMutex<RefCell<MutexState>>,
+}
+
+unsafe impl RawMutex for RawBlockingMutex {
+    const INIT: Self = RawBlockingMutex {
+        state: NakedMutex::new(RefCell::new(MutexState::new())),
+    };
+
+    // The scheduler tracks which task holds the lock, so it has to be unlocked by the same task
+    type GuardMarker = GuardNoSend;
+
+    fn lock(&self) {
+        scheduler::lock_mutex(&self.state)
+    }
+
+    fn try_lock(&self) -> bool {
+        scheduler::try_lock_mutex(&self.state)
+    }
+
+    unsafe fn unlock(&self) {
+        scheduler::unlock_mutex(&self.state)
+    }
+}
+
+pub type Mutex<T> = lock_api::Mutex<RawBlockingMutex, T>;
+pub type MutexGuard<'a, T> = lock_api::MutexGuard<'a, RawBlockingMutex, T>;
+
+// Only read or written with the scheduler spinlock held
+pub(crate) struct MutexState {
+    pub(crate) locked: bool,
+    // None while held from outside a task
+    pub(crate) owner: Option<usize>,
+    pub(crate) waiters: Vec<usize>,
+}
+
+impl MutexState {
+    const fn new() -> Self {
+        Self {
+            locked: false,
+            owner: None,
+            waiters: Vec::new(),
+        }
+    }
+}
+
+// Tasks blocked on one of the primitives below, only touched with the scheduler spinlock held
+pub(crate) struct WaitList(Vec<usize>);
+
+impl WaitList {
+    pub(crate) const fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub(crate) fn add(&mut self, task_id: usize) {
+        if !self.0.contains(&task_id) {
+            self.0.push(task_id);
+        }
+    }
+
+    pub(crate) fn remove(&mut self, task_id: usize) {
+        self.0.retain(|waiter| *waiter != task_id);
+    }
+
+    pub(crate) fn contains(&self, task_id: usize) -> bool {
+        self.0.contains(&task_id)
+    }
+
+    pub(crate) fn take_first(&mut self) -> Option<usize> {
+        if self.0.is_empty() {
+            None
+        } else {
+            Some(self.0.remove(0))
+        }
+    }
+}
+
+// Reads or updates a primitive's state without waking anything, the scheduler spinlock guards all of them
+fn with_state<S, R>(primitive: &NakedMutex<RefCell<S>>, f: impl FnOnce(&mut S) -> R) -> R {
+    cross_core_free(SCHEDULER_SPINLOCK, || {
+        f(&mut primitive.borrow().borrow_mut())
+    })
+}
+
+// Timeouts are given as a duration but waited on as a deadline, so retries don't extend the wait
+pub(crate) fn deadline_after(timeout_us: Option<u32>) -> Option<u32> {
+    timeout_us.map(|timeout_us| scheduler::now_us().wrapping_add(timeout_us))
+}
+
+/// Counting semaphore, tasks taking a permit while none are left block in the scheduler until one is released
+pub struct Semaphore {
+    state: NakedMutex<RefCell<SemaphoreState>>,
+}
+
+struct SemaphoreState {
+    permits: usize,
+    waiters: WaitList,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Self {
+            state: NakedMutex::new(RefCell::new(SemaphoreState {
+                permits,
+                waiters: WaitList::new(),
+            })),
+        }
+    }
+
+    /// Takes a permit, blocking until one is released
+    pub fn acquire(&self) {
+        self.acquire_timeout(None);
+    }
+
+    /// Takes a permit, giving up after `timeout_us` microseconds (None waits forever). Returns false if it timed out.
+    pub fn acquire_timeout(&self, timeout_us: Option<u32>) -> bool {
+        scheduler::wait_for(
+            &self.state,
+            deadline_after(timeout_us),
+            |state| &mut state.waiters,
+            |state, _| {
+                if state.permits > 0 {
+                    state.permits -= 1;
+                    Some(())
+                } else {
+                    None
+                }
+            },
+        )
+        .is_some()
+    }
+
+    /// Takes a permit if one is free without blocking, this is safe to call from interrupts
+    pub fn try_acquire(&self) -> bool {
+        self.acquire_timeout(Some(0))
+    }
+
+    /// Returns a permit and wakes the tasks waiting for one, this is safe to call from interrupts
+    pub fn release(&self) {
+        scheduler::notify(
+            &self.state,
+            |state| &mut state.waiters,
+            true,
+            |state| state.permits += 1,
+        );
+    }
+
+    pub fn available(&self) -> usize {
+        with_state(&self.state, |state| state.permits)
+    }
+}
+
+/// How `EventFlags::wait` matches the bits it is waiting on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventWait {
+    /// Any one of the bits being set ends the wait
+    Any,
+    /// Every one of the bits has to be set
+    All,
+}
+
+/// Group of 32 event bits, tasks block until any or all of the bits they wait on are set. Setting bits is safe from
+/// interrupts, so a handler can signal tasks without them polling.
+pub struct EventFlags {
+    state: NakedMutex<RefCell<EventFlagsState>>,
+}
+
+struct EventFlagsState {
+    flags: u32,
+    waiters: WaitList,
+}
+
+impl EventFlags {
+    pub const fn new() -> Self {
+        Self {
+            state: NakedMutex::new(RefCell::new(EventFlagsState {
+                flags: 0,
+                waiters: WaitList::new(),
+            })),
+        }
+    }
+
+    /// Sets `bits` and wakes every waiting task to check them
+    pub fn set(&self, bits: u32) {
+        scheduler::notify(
+            &self.state,
+            |state| &mut state.waiters,
+            true,
+            |state| state.flags |= bits,
+        );
+    }
+
+    pub fn clear(&self, bits: u32) {
+        with_state(&self.state, |state| state.flags &= !bits);
+    }
+
+    pub fn get(&self) -> u32 {
+        with_state(&self.state, |state| state.flags)
+    }
+
+    /// Blocks until any or all of `bits` are set, giving up after `timeout_us` microseconds (None waits forever).
+    /// Returns the flags as they were when the wait ended, or None if it timed out. With `clear` the waited on
+    /// bits are cleared as the wait ends, so only one task sees each event.
+    pub fn wait(
+        &self,
+        bits: u32,
+        mode: EventWait,
+        clear: bool,
+        timeout_us: Option<u32>,
+    ) -> Option<u32> {
+        scheduler::wait_for(
+            &self.state,
+            deadline_after(timeout_us),
+            |state| &mut state.waiters,
+            |state, _| {
+                let matched = match mode {
+                    EventWait::Any => state.flags & bits != 0,
+                    EventWait::All => state.flags & bits == bits,
+                };
+                if !matched {
+                    return None;
+                }
+
+                let flags = state.flags;
+                if clear {
+                    state.flags &= !bits;
+                }
+                Some(flags)
+            },
+        )
+    }
+}
+
+impl Default for EventFlags {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Condition variable for waiting on state behind a `Mutex`. Wakeups can be spurious, so always wait in a loop
+/// that re-checks the condition.
+pub struct Condvar {
+    waiters: NakedMutex<RefCell<WaitList>>,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            waiters: NakedMutex::new(RefCell::new(WaitList::new())),
+        }
+    }
+
+    /// Unlocks the mutex behind `guard`, blocks until notified and locks it again
+    pub fn wait<T>(&self, guard: &mut MutexGuard<'_, T>) {
+        self.wait_timeout(guard, None);
+    }
+
+    /// `wait`, giving up after `timeout_us` microseconds (None waits forever). Returns false if it timed out.
+    pub fn wait_timeout<T>(&self, guard: &mut MutexGuard<'_, T>, timeout_us: Option<u32>) -> bool {
+        let deadline = deadline_after(timeout_us);
+
+        // Queued while the mutex is still held, so a notify straight after it is unlocked isn't missed
+        scheduler::enqueue(&self.waiters, |waiters| waiters);
+
+        MutexGuard::unlocked(guard, || {
+            scheduler::wait_for(
+                &self.waiters,
+                deadline,
+                |waiters| waiters,
+                // Notifying takes the task off the list
+                |waiters, caller| match caller {
+                    Some(caller) if waiters.contains(caller) => None,
+                    _ => Some(()),
+                },
+            )
+            .is_some()
+        })
+    }
+
+    /// Wakes the task that has been waiting longest
+    pub fn notify_one(&self) {
+        scheduler::notify(&self.waiters, |waiters| waiters, false, |_| ());
+    }
+
+    pub fn notify_all(&self) {
+        scheduler::notify(&self.waiters, |waiters| waiters, true, |_| ());
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Runs `f` with interrupts disabled on this core and SIO hardware spinlock `lock` held, so the other core is kept
+/// out as well. The M0+ has no atomic read-modify-write, reading a hardware spinlock claims it in a single access.
+pub(crate) fn cross_core_free<R>(lock: usize, f: impl FnOnce() -> R) -> R {
+    cortex_m::interrupt::free(|_| {
+        let sio = unsafe { &*pac::SIO::ptr() };
+        while sio.spinlock[lock].read().bits() == 0 {}
+        // The register accesses are volatile but nothing stops the compiler moving `f`'s memory accesses past them
+        compiler_fence(Ordering::Acquire);
+
+        let result = f();
+
+        compiler_fence(Ordering::Release);
+        sio.spinlock[lock].write(|w| unsafe { w.bits(1) });
+        result
+    })
+}
+
+/// Frees the kernel's hardware spinlocks. They are only cleared by resetting the SIO, so a core reset (such as from
+/// a debugger) part way through a critical section leaves them taken. Must be called before the other core is running.
+pub unsafe fn release_kernel_spinlocks() {
+    let sio = &*pac::SIO::ptr();
+    for lock in [SCHEDULER_SPINLOCK, HEAP_SPINLOCK, LOCK_WORD_SPINLOCK] {
+        sio.spinlock[lock].write(|w| w.bits(1));
+    }
+}
+
+/// `CortexMHeap` only masks interrupts on the calling core, this also holds a hardware spinlock so both cores can
+/// allocate
+pub struct SharedHeap {
+    heap: CortexMHeap,
+}
+
+impl SharedHeap {
+    pub const fn empty() -> Self {
+        Self {
+            heap: CortexMHeap::empty(),
+        }
+    }
+
+    /// See `CortexMHeap::init`, this must only be called once
+    pub unsafe fn init(&self, start_addr: usize, size: usize) {
+        self.heap.init(start_addr, size)
+    }
+}
+
+unsafe impl GlobalAlloc for SharedHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        cross_core_free(HEAP_SPINLOCK, || self.heap.alloc(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        cross_core_free(HEAP_SPINLOCK, || self.heap.dealloc(ptr, layout))
+    }
+}